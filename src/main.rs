@@ -1,17 +1,116 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, File, OpenOptions};
-use std::io::{BufRead, BufReader, Write, stdin};
+use std::io::{BufRead, BufReader, IsTerminal, Write, stdin};
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use chrono::Local;
-use serde::Deserialize;
-use tokio::sync::Mutex;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Semaphore};
 use tokio::time;
 
-#[derive(Debug, Deserialize, Clone)]
+static HUMAN_OUTPUT_TO_STDERR: AtomicBool = AtomicBool::new(false);
+static RUN_STARTED: std::sync::Mutex<Option<std::time::Instant>> = std::sync::Mutex::new(None);
+static PROGRESS_BARS: AtomicBool = AtomicBool::new(false);
+static ACTIVE_PROGRESS: std::sync::Mutex<Option<MultiProgress>> = std::sync::Mutex::new(None);
+static CPU_AFFINITY: OnceLock<Vec<core_affinity::CoreId>> = OnceLock::new();
+static NEXT_AFFINITY_CORE: AtomicUsize = AtomicUsize::new(0);
+static PROCESS_LAUNCHER: OnceLock<std::sync::mpsc::Sender<LaunchRequest>> = OnceLock::new();
+
+thread_local! {
+    static THREAD_PINNED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+type LaunchRequest = (Command, std::sync::mpsc::Sender<std::io::Result<Child>>);
+
+macro_rules! console {
+    () => {
+        console_write("")
+    };
+    ($($arg:tt)*) => {
+        console_write(&format!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Stdout,
+    Stderr,
+}
+
+/// Writes a human-readable message; it goes to stderr under `--stream-json`.
+fn console_write(message: &str) {
+    let output = if HUMAN_OUTPUT_TO_STDERR.load(Ordering::Relaxed) {
+        Output::Stderr
+    } else {
+        Output::Stdout
+    };
+    write_output(output, message);
+}
+
+/// Writes one logical message, possibly several lines, with a single locked
+/// write so that output from concurrent tasks never interleaves mid-message.
+fn write_output(output: Output, message: &str) {
+    let mut text = String::with_capacity(message.len() + 1);
+    text.push_str(message);
+    text.push('\n');
+
+    let write = || {
+        let _ = match output {
+            Output::Stderr => std::io::stderr().lock().write_all(text.as_bytes()),
+            Output::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(text.as_bytes()).and_then(|()| stdout.flush())
+            }
+        };
+    };
+
+    // Progress bars are redrawn below the message instead of being overwritten.
+    let progress = ACTIVE_PROGRESS.lock().ok().and_then(|active| active.clone());
+    match progress {
+        Some(progress) => progress.suspend(write),
+        None => write(),
+    }
+}
+
+/// Container for the per-config progress bars of one group; hidden unless
+/// progress output is enabled. While visible, console output goes around it.
+fn start_group_progress() -> MultiProgress {
+    if !PROGRESS_BARS.load(Ordering::Relaxed) {
+        return MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let progress = MultiProgress::new();
+    if let Ok(mut active) = ACTIVE_PROGRESS.lock() {
+        *active = Some(progress.clone());
+    }
+    progress
+}
+
+fn finish_group_progress(progress: &MultiProgress) {
+    if let Ok(mut active) = ACTIVE_PROGRESS.lock() {
+        *active = None;
+    }
+    let _ = progress.clear();
+}
+
+fn config_progress_bar(progress: &MultiProgress, config: &str, domains: usize) -> ProgressBar {
+    let style = ProgressStyle::with_template("   {prefix:24!} [{bar:30}] {pos}/{len}")
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("=> ");
+    let bar = progress.add(ProgressBar::new(domains as u64).with_style(style));
+    bar.set_prefix(extract_config_name(config));
+    bar
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct Settings {
     group_size: usize,
     start_port: u16,
@@ -20,669 +119,6786 @@ struct Settings {
     log_dir: String,
     results_file: String,
     ciadpi_start_delay_ms: u64,
+    #[serde(default)]
+    fail_status: Vec<u16>,
+    #[serde(default = "default_true")]
+    count_timeouts: bool,
+    #[serde(default = "default_true")]
+    count_connect_errors: bool,
+    #[serde(default)]
+    results_sqlite: Option<String>,
+    #[serde(default)]
+    results_html_file: Option<String>,
+    #[serde(default)]
+    matrix_file: Option<String>,
+    #[serde(default)]
+    logs_optional: bool,
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    slow_start_initial: Option<usize>,
+    #[serde(default)]
+    slow_start_ms: u64,
+    #[serde(default = "default_true")]
+    scale_partial_group: bool,
+    #[serde(default)]
+    port_pool: Vec<u16>,
+    #[serde(default)]
+    max_reuses_per_port: Option<usize>,
+    #[serde(default)]
+    tcp_connect_only: bool,
+    #[serde(default)]
+    ciadpi_start_delay_jitter_ms: u64,
+    #[serde(default)]
+    bottom_configs_count: usize,
+    #[serde(default)]
+    results_file_per_session: bool,
+    #[serde(default)]
+    max_groups: Option<usize>,
+    #[serde(default)]
+    baseline_config: Option<String>,
+    #[serde(default)]
+    per_config_concurrency: Option<usize>,
+    #[serde(default = "default_https_attempts")]
+    https_attempts_before_fallback: u32,
+    #[serde(default = "default_connections_per_domain")]
+    connections_per_domain: usize,
+    #[serde(default = "default_connections_success_fraction")]
+    connections_success_fraction: f32,
+    #[serde(default)]
+    exit_when_done: bool,
+    #[serde(default)]
+    post_run_hook: Option<String>,
+    #[serde(default = "default_watch_history_file")]
+    watch_history_file: String,
+    #[serde(default)]
+    spawn_retries: u32,
+    #[serde(default = "default_spawn_retry_delay_ms")]
+    spawn_retry_delay_ms: u64,
+    #[serde(default)]
+    bind_retries: u32,
+    #[serde(default)]
+    reserve_ports: Vec<u16>,
+    #[serde(default)]
+    port_rebind_min_interval_ms: u64,
+    #[serde(default)]
+    group_abort_on_spawn_failures: Option<f32>,
+    #[serde(default)]
+    extra_ciadpi_args: Vec<String>,
+    #[serde(default = "default_repeat_count")]
+    repeat_count: u32,
+    #[serde(default)]
+    require_both_schemes: bool,
+    #[serde(default)]
+    try_www_variant: bool,
+    #[serde(default)]
+    local_dns: bool,
+    #[serde(default)]
+    keep_logs: KeepLogs,
+    #[serde(default)]
+    group_order: GroupOrder,
+    #[serde(default)]
+    require_header: Option<String>,
+    #[serde(default)]
+    rules_file: Option<String>,
+    #[serde(skip)]
+    rules: SuccessRules,
+    #[serde(default = "default_latency_buckets_ms")]
+    latency_buckets_ms: Vec<u64>,
+    #[serde(default = "default_stability_interval_ms")]
+    stability_interval_ms: u64,
+    #[serde(default)]
+    tier_thresholds: Option<TierThresholds>,
+    #[serde(default)]
+    recommend_thresholds: Option<RecommendThresholds>,
+    #[serde(default)]
+    top_min_success_rate: Option<f32>,
+    #[serde(default)]
+    max_listed_domains: Option<usize>,
+    #[serde(default)]
+    max_config_display_len: Option<usize>,
+    #[serde(default)]
+    direct_baseline: bool,
+    #[serde(default)]
+    direct_baseline_parallel: bool,
+    #[serde(default = "default_fixed_ciadpi_args")]
+    fixed_ciadpi_args: Vec<String>,
+    #[serde(default = "default_true")]
+    detail_include_successful: bool,
+    #[serde(default)]
+    pre_kill_grace_ms: u64,
+    #[serde(default)]
+    max_log_files: Option<usize>,
+    #[serde(default)]
+    stream_results: bool,
+    #[serde(default)]
+    disable_keepalive: bool,
+    #[serde(default = "default_true")]
+    tcp_nodelay: bool,
+    #[serde(default)]
+    tcp_keepalive_sec: Option<u64>,
+    #[serde(default)]
+    tls_fingerprint: TlsFingerprint,
+    #[serde(default)]
+    enable_http3: bool,
+    #[serde(default)]
+    cpu_affinity: Vec<usize>,
+    #[serde(default)]
+    persistent_check: Option<PersistentCheck>,
+    #[serde(default)]
+    proxy_address_family: AddressFamily,
+    #[serde(default = "default_repeat_count")]
+    suite_repeats: u32,
+    #[serde(default)]
+    domain_success_quorum: Option<DomainSuccessQuorum>,
+    #[serde(default)]
+    control_domain: Option<String>,
+    #[serde(default = "default_min_domains_warning")]
+    min_domains_warning: usize,
+    #[serde(skip)]
+    stream_json: bool,
 }
 
-#[derive(Debug, Clone)]
-struct TestResult {
-    config: String,
-    socks5_port: u16,
-    successful_domains: Vec<String>,
-    failed_domains: Vec<String>,
-    success_rate: f32,
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct TierThresholds {
+    excellent: f32,
+    good: f32,
 }
 
-impl TestResult {
-    fn new(config: String, socks5_port: u16, successful: Vec<String>, failed: Vec<String>) -> Self {
-        let total = successful.len() + failed.len();
-        let success_rate = if total > 0 {
-            (successful.len() as f32 / total as f32) * 100.0
+/// What counts as a successful response. Built from `fail_status` and
+/// `require_header`, or loaded from `rules_file` when it is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SuccessRules {
+    #[serde(default)]
+    accepted_status: Vec<u16>,
+    #[serde(default)]
+    fail_status: Vec<u16>,
+    #[serde(default)]
+    require_headers: Vec<String>,
+    #[serde(default)]
+    forbidden_body: Vec<String>,
+}
+
+impl SuccessRules {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            fail_status: settings.fail_status.clone(),
+            require_headers: settings.require_header.iter().cloned().collect(),
+            ..Self::default()
+        }
+    }
+
+    fn load(path: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let rules = if path.ends_with(".json") {
+            serde_json::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path, e))?
         } else {
-            0.0
+            toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path, e))?
         };
+        Ok(rules)
+    }
 
-        Self {
-            config,
-            socks5_port,
-            successful_domains: successful,
-            failed_domains: failed,
-            success_rate,
+    fn accepts_status(&self, status: reqwest::StatusCode) -> bool {
+        if self.fail_status.contains(&status.as_u16()) {
+            return false;
+        }
+        if self.accepted_status.is_empty() {
+            status.is_success()
+        } else {
+            self.accepted_status.contains(&status.as_u16())
         }
     }
+
+    fn accepts_headers(&self, headers: &reqwest::header::HeaderMap) -> bool {
+        self.require_headers
+            .iter()
+            .all(|required| has_required_header(headers, required))
+    }
+
+    fn forbidden_match(&self, body: &[u8]) -> Option<&str> {
+        let body = String::from_utf8_lossy(body);
+        self.forbidden_body
+            .iter()
+            .find(|marker| body.contains(marker.as_str()))
+            .map(String::as_str)
+    }
 }
 
-struct GroupStats {
-    successful: usize,
-    total: usize,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistentCheck {
+    target: String,
+    requests: u32,
+    #[serde(default = "default_persistent_interval_ms")]
+    interval_ms: u64,
 }
 
-impl GroupStats {
-    fn success_rate(&self) -> f32 {
-        if self.total > 0 {
-            (self.successful as f32 / self.total as f32) * 100.0
-        } else {
-            0.0
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RecommendThresholds {
+    min_success_rate: f32,
+    max_median_latency_ms: u64,
+    min_attempt_success_rate: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AddressFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn proxy_address(self, port: u16) -> std::net::SocketAddr {
+        let ip = match self {
+            AddressFamily::Ipv4 => std::net::IpAddr::from(std::net::Ipv4Addr::LOCALHOST),
+            AddressFamily::Ipv6 => std::net::IpAddr::from(std::net::Ipv6Addr::LOCALHOST),
+        };
+        std::net::SocketAddr::new(ip, port)
+    }
+}
+
+/// ClientHello the HTTPS checks present. Anything but `default` needs a build
+/// with the `browser-tls` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TlsFingerprint {
+    #[default]
+    Default,
+    Chrome,
+    Firefox,
+}
+
+impl TlsFingerprint {
+    fn name(self) -> &'static str {
+        match self {
+            TlsFingerprint::Default => "default",
+            TlsFingerprint::Chrome => "chrome",
+            TlsFingerprint::Firefox => "firefox",
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    show_welcome_message();
-    wait_for_start();
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum KeepLogs {
+    #[default]
+    All,
+    Failed,
+    None,
+}
 
-    let settings = load_settings()?;
-    let configs = read_lines("configs.txt")?;
-    let domains = read_lines("domains.txt")?;
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GroupOrder {
+    #[default]
+    Sequential,
+    Reverse,
+    Random,
+}
 
-    display_startup_info(&settings, &configs, &domains);
-    confirm_start()?;
+/// How many successes make a domain count as reached: one number for both
+/// `repeat_count` attempts and `suite_repeats` runs, or `{ attempts, runs }`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+enum DomainSuccessQuorum {
+    Shared(u32),
+    Split {
+        #[serde(default)]
+        attempts: Option<u32>,
+        #[serde(default)]
+        runs: Option<u32>,
+    },
+}
 
-    let session_dir = create_session_directory(&settings.log_dir)?;
-    let results = Arc::new(Mutex::new(Vec::new()));
+impl DomainSuccessQuorum {
+    fn attempts(&self) -> Option<u32> {
+        match *self {
+            DomainSuccessQuorum::Shared(quorum) => Some(quorum),
+            DomainSuccessQuorum::Split { attempts, .. } => attempts,
+        }
+    }
 
-    let group_stats = run_all_groups(&configs, &domains, &settings, &session_dir, results.clone()).await?;
+    fn runs(&self) -> Option<u32> {
+        match *self {
+            DomainSuccessQuorum::Shared(quorum) => Some(quorum),
+            DomainSuccessQuorum::Split { runs, .. } => runs,
+        }
+    }
+}
 
-    finalize_results(results, &settings, &group_stats, &session_dir).await?;
-    wait_for_quit();
+fn default_true() -> bool {
+    true
+}
 
-    Ok(())
+fn default_spawn_retry_delay_ms() -> u64 {
+    100
 }
 
-fn load_settings() -> Result<Settings, Box<dyn std::error::Error + Send + Sync>> {
-    print_status("[+]", "Загружаем настройки из settings.toml...");
-    let content = std::fs::read_to_string("settings.toml")
-        .map_err(|e| format!("Failed to read settings.toml: {}", e))?;
-    
-    toml::from_str(&content)
-        .map_err(|e| format!("Failed to parse settings.toml: {}", e).into())
+fn default_repeat_count() -> u32 {
+    1
 }
 
-fn read_lines(filename: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
-    let file = File::open(filename)
-        .map_err(|e| format!("Failed to open {}: {}", filename, e))?;
-    
-    BufReader::new(file)
-        .lines()
-        .map(|line| line.map(|l| l.trim().to_string()))
-        .filter(|line| line.as_ref().map(|l| !l.is_empty()).unwrap_or(false))
-        .collect::<Result<Vec<_>, _>>()
-        .map_err(|e| format!("Failed to read lines from {}: {}", filename, e).into())
+fn default_watch_history_file() -> String {
+    "watch_history.jsonl".to_string()
 }
 
-fn create_session_directory(log_dir: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
-    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
-    let session_dir = PathBuf::from(log_dir).join(timestamp.to_string());
-    create_dir_all(&session_dir)?;
-    Ok(session_dir)
+fn default_https_attempts() -> u32 {
+    1
 }
 
-async fn run_all_groups(
-    configs: &[String],
-    domains: &[String],
-    settings: &Settings,
-    session_dir: &Path,
-    results: Arc<Mutex<Vec<TestResult>>>,
-) -> Result<Vec<GroupStats>, Box<dyn std::error::Error + Send + Sync>> {
-    let total_groups = (configs.len() + settings.group_size - 1) / settings.group_size;
-    let mut group_stats = Vec::with_capacity(total_groups);
+fn default_connections_per_domain() -> usize {
+    1
+}
 
-    for (group_idx, chunk) in configs.chunks(settings.group_size).enumerate() {
-        let group_number = group_idx + 1;
-        
-        print_group_header(group_number, total_groups, chunk.len(), settings.start_port);
-        
-        let group_dir = session_dir.join(format!("group_{}", group_number));
-        create_dir_all(&group_dir)?;
+fn default_connections_success_fraction() -> f32 {
+    1.0
+}
 
-        let stats = process_group(
-            chunk,
-            domains,
-            settings,
-            &group_dir,
-            results.clone(),
-        ).await?;
+fn default_min_domains_warning() -> usize {
+    5
+}
 
-        print_group_summary(group_number, &stats);
-        group_stats.push(stats);
+fn default_latency_buckets_ms() -> Vec<u64> {
+    vec![100, 250, 500, 1000, 2000, 5000]
+}
 
-        save_intermediate_results(&results, &settings.results_file).await?;
+fn default_stability_interval_ms() -> u64 {
+    5000
+}
 
-        if group_number < total_groups {
-            wait_between_groups(settings.group_delay_ms).await;
-        }
+fn default_persistent_interval_ms() -> u64 {
+    1000
+}
+
+fn default_fixed_ciadpi_args() -> Vec<String> {
+    let mut args = vec!["--ip", "0.0.0.0", "--port", "{port}"];
+    if !cfg!(windows) {
+        args.push("-Y");
     }
+    args.into_iter().map(String::from).collect()
+}
 
-    Ok(group_stats)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TestResult {
+    config: String,
+    socks5_port: u16,
+    successful_domains: Vec<String>,
+    failed_domains: Vec<String>,
+    excluded_domains: Vec<String>,
+    failure_reasons: HashMap<String, FailureKind>,
+    bytes_received: u64,
+    attempts_successful: usize,
+    attempts_total: usize,
+    latencies_ms: Vec<u64>,
+    rescued_domains: Option<Vec<String>>,
+    #[serde(default)]
+    network_down: bool,
+    #[serde(default)]
+    www_variant_domains: Vec<String>,
+    #[serde(default)]
+    http3_successful_domains: Vec<String>,
+    #[serde(default)]
+    http3_failed_domains: Vec<String>,
+    #[serde(default)]
+    persistent_successes: Option<u32>,
+    #[serde(default)]
+    resolved_ips: HashMap<String, String>,
+    #[serde(default)]
+    ciadpi_error: Option<String>,
+    #[serde(default)]
+    started_sec: Option<f64>,
+    #[serde(default)]
+    finished_sec: Option<f64>,
+    #[serde(default)]
+    finished_at: Option<String>,
+    success_rate: f32,
 }
 
-async fn process_group(
-    configs: &[String],
-    domains: &[String],
-    settings: &Settings,
-    group_dir: &Path,
-    results: Arc<Mutex<Vec<TestResult>>>,
-) -> Result<GroupStats, Box<dyn std::error::Error + Send + Sync>> {
-    let mut tasks = Vec::with_capacity(configs.len());
+impl TestResult {
+    fn new(config: String, socks5_port: u16, domains: DomainResults) -> Self {
+        let total = domains.successful.len() + domains.failed.len();
+        let success_rate = if total > 0 {
+            (domains.successful.len() as f32 / total as f32) * 100.0
+        } else {
+            0.0
+        };
 
-    for (i, config) in configs.iter().enumerate() {
-        let socks5_port = settings.start_port + i as u16;
-        let task = spawn_config_test(
-            config.clone(),
+        Self {
+            config,
             socks5_port,
-            domains.to_vec(),
-            group_dir.to_path_buf(),
-            settings.clone(),
-            results.clone(),
+            successful_domains: domains.successful,
+            failed_domains: domains.failed,
+            excluded_domains: domains.excluded,
+            failure_reasons: domains.failure_reasons,
+            bytes_received: domains.bytes_received,
+            attempts_successful: domains.attempts_successful,
+            attempts_total: domains.attempts_total,
+            latencies_ms: domains.latencies_ms,
+            rescued_domains: None,
+            network_down: false,
+            www_variant_domains: domains.www_variant,
+            http3_successful_domains: domains.http3_successful,
+            http3_failed_domains: domains.http3_failed,
+            persistent_successes: None,
+            resolved_ips: domains.resolved_ips,
+            ciadpi_error: None,
+            started_sec: None,
+            finished_sec: None,
+            finished_at: None,
+            success_rate,
+        }
+    }
+
+    /// Re-splits the tested domains by how many suite runs reached each one, so
+    /// ranking counts a domain as successful only when it met the quorum.
+    fn apply_suite_quorum(&mut self, passes: &HashMap<String, u32>, quorum: u32) {
+        let reached = |domain: &String| passes.get(domain).is_some_and(|&runs| runs >= quorum);
+        let (successful, failed): (Vec<String>, Vec<String>) = self
+            .successful_domains
+            .drain(..)
+            .chain(self.failed_domains.drain(..))
+            .partition(|domain| reached(domain));
+        self.failure_reasons.retain(|domain, _| !reached(domain));
+        let total = successful.len() + failed.len();
+        self.success_rate = if total > 0 {
+            successful.len() as f32 / total as f32 * 100.0
+        } else {
+            0.0
+        };
+        self.successful_domains = successful;
+        self.failed_domains = failed;
+    }
+
+    fn apply_direct_baseline(&mut self, direct_failed: &HashSet<String>) {
+        self.rescued_domains = Some(
+            self.successful_domains
+                .iter()
+                .filter(|domain| direct_failed.contains(*domain))
+                .cloned()
+                .collect(),
         );
-        
-        print_config_start(config, socks5_port);
-        tasks.push(task);
     }
 
-    let mut successful_total = 0;
-    let mut tests_total = 0;
+    fn confidence_interval(&self) -> (f32, f32) {
+        wilson_interval(self.attempts_successful, self.attempts_total)
+    }
 
-    for (i, task) in tasks.into_iter().enumerate() {
-        match task.await {
-            Ok(Some((config_name, successful, total))) => {
-                successful_total += successful;
-                tests_total += total;
-                print_config_result(&config_name, successful, total);
-            }
-            Ok(None) => print_config_error(&configs[i]),
-            Err(e) => eprintln!("   [ERROR] Task execution failed: {:?}", e),
+    fn rank(&self) -> ConfigRank {
+        ConfigRank {
+            config: self.config.clone(),
+            socks5_port: self.socks5_port,
+            successful: self.successful_domains.len(),
+            failed: self.failed_domains.len(),
+            success_rate: self.success_rate,
+            confidence: self.confidence_interval(),
+            dominant_failure: self.dominant_failure(),
+            rescued: self.rescued_domains.as_ref().map(Vec::len),
+            median_latency_ms: median(&self.latencies_ms),
+            attempt_success_rate: if self.attempts_total > 0 {
+                self.attempts_successful as f32 / self.attempts_total as f32 * 100.0
+            } else {
+                0.0
+            },
         }
     }
 
-    Ok(GroupStats {
-        successful: successful_total,
-        total: tests_total,
-    })
+    fn dominant_failure(&self) -> Option<(FailureKind, usize)> {
+        let mut counts: Vec<(FailureKind, usize)> = Vec::new();
+        for domain in &self.failed_domains {
+            let kind = self.failure_reasons.get(domain).copied().unwrap_or(FailureKind::Other);
+            match counts.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((kind, 1)),
+            }
+        }
+        counts.into_iter().max_by_key(|(_, count)| *count)
+    }
 }
 
-fn spawn_config_test(
+struct ConfigRank {
     config: String,
     socks5_port: u16,
-    domains: Vec<String>,
-    group_dir: PathBuf,
-    settings: Settings,
-    results: Arc<Mutex<Vec<TestResult>>>,
-) -> tokio::task::JoinHandle<Option<(String, usize, usize)>> {
-    tokio::spawn(async move {
-        run_config_test(&config, socks5_port, &domains, &group_dir, &settings, results)
-            .await
-            .ok()
-    })
+    successful: usize,
+    failed: usize,
+    success_rate: f32,
+    confidence: (f32, f32),
+    dominant_failure: Option<(FailureKind, usize)>,
+    rescued: Option<usize>,
+    median_latency_ms: Option<u64>,
+    attempt_success_rate: f32,
 }
 
-async fn run_config_test(
-    config: &str,
-    socks5_port: u16,
-    domains: &[String],
-    group_dir: &Path,
-    settings: &Settings,
-    results: Arc<Mutex<Vec<TestResult>>>,
-) -> Result<(String, usize, usize), Box<dyn std::error::Error + Send + Sync>> {
-    let mut process = start_ciadpi_process(config, socks5_port, group_dir)?;
-    time::sleep(Duration::from_millis(settings.ciadpi_start_delay_ms)).await;
-
-    let (successful, failed) = test_all_domains(domains, socks5_port, settings.request_timeout_sec).await;
+impl ConfigRank {
+    fn total(&self) -> usize {
+        self.successful + self.failed
+    }
 
-    stop_process(&mut process);
+    fn is_recommended(&self, thresholds: RecommendThresholds) -> bool {
+        self.success_rate >= thresholds.min_success_rate
+            && self.attempt_success_rate >= thresholds.min_attempt_success_rate
+            && self
+                .median_latency_ms
+                .is_some_and(|latency| latency <= thresholds.max_median_latency_ms)
+    }
+}
 
-    let total_tests = successful.len() + failed.len();
-    let result = TestResult::new(config.to_string(), socks5_port, successful.clone(), failed);
-    
-    results.lock().await.push(result);
+fn median(values: &[u64]) -> Option<u64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied()
+}
 
-    let config_name = extract_config_name(config);
-    Ok((config_name, successful.len(), total_tests))
+struct ResultStore {
+    results: Vec<TestResult>,
+    stream_path: Option<PathBuf>,
+    direct_failed: Option<HashSet<String>>,
+    suite_quorum: Option<(DomainPasses, u32)>,
 }
 
-fn start_ciadpi_process(
-    config: &str,
-    socks5_port: u16,
-    group_dir: &Path,
-) -> Result<Child, Box<dyn std::error::Error + Send + Sync>> {
-    let exe_name = if cfg!(windows) { "ciadpi.exe" } else { "./ciadpi" };
-    let log_file_name = sanitize_filename(config, socks5_port);
-    let log_path = group_dir.join(format!("ciadpi_{}.log", log_file_name));
-    
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&log_path)?;
+impl ResultStore {
+    fn new(settings: &Settings) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let stream_path = if settings.stream_results {
+            let path = Path::new(&settings.results_file).with_extension("ndjson");
+            File::create(&path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            Some(path)
+        } else {
+            None
+        };
 
-    let args: Vec<&str> = config.split_whitespace().collect();
-    
-    if cfg!(windows) { 
-        Command::new(exe_name)
-            .args(&args)
-            .args(&["--ip", "0.0.0.0", "--port", &socks5_port.to_string()])
-            .stdout(Stdio::from(log_file.try_clone()?))
-            .stderr(Stdio::from(log_file))
-            .spawn()
-            .map_err(|e| format!("Failed to spawn {} ({}): {}", exe_name, config, e).into())
-    } else {
-        Command::new(exe_name)
-            .args(&args)
-            .args(&["--ip", "0.0.0.0", "--port", &socks5_port.to_string(), "-Y"])
-            .stdout(Stdio::from(log_file.try_clone()?))
-            .stderr(Stdio::from(log_file))
-            .spawn()
-            .map_err(|e| format!("Failed to spawn {} ({}): {}", exe_name, config, e).into())
+        Ok(Self {
+            results: Vec::new(),
+            stream_path,
+            direct_failed: None,
+            suite_quorum: None,
+        })
     }
-}
-
-fn stop_process(child: &mut Child) {
-    let _ = child.kill();
-    let _ = child.wait();
-}
 
-async fn test_all_domains(
-    domains: &[String],
-    port: u16,
-    timeout_sec: u64,
-) -> (Vec<String>, Vec<String>) {
-    let tasks: Vec<_> = domains
-        .iter()
-        .map(|domain| test_domain(domain.clone(), port, timeout_sec))
-        .collect();
+    fn push(&mut self, result: TestResult) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(path) = &self.stream_path else {
+            self.results.push(result);
+            return Ok(());
+        };
 
-    let results = futures::future::join_all(tasks).await;
-    
-    let (successful, failed): (Vec<_>, Vec<_>) =
-        results.into_iter().partition(|(_, success)| *success);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        writeln!(file, "{}", serde_json::to_string(&result)?)?;
+        Ok(())
+    }
 
-    let successful_domains = successful.into_iter().map(|(domain, _)| domain).collect::<Vec<_>>();
-    let failed_domains = failed.into_iter().map(|(domain, _)| domain).collect::<Vec<_>>();
+    /// Applies the `suite_repeats` quorum to every result; call before
+    /// `set_direct_baseline`, which works on the final successful domains.
+    fn set_suite_quorum(&mut self, domain_passes: DomainPasses, quorum: u32) {
+        for result in &mut self.results {
+            if let Some(passes) = domain_passes.get(&result.config) {
+                result.apply_suite_quorum(passes, quorum);
+            }
+        }
+        self.suite_quorum = Some((domain_passes, quorum));
+    }
 
-    (successful_domains, failed_domains)
-}
+    fn set_direct_baseline(&mut self, direct_failed: HashSet<String>) {
+        for result in &mut self.results {
+            result.apply_direct_baseline(&direct_failed);
+        }
+        self.direct_failed = Some(direct_failed);
+    }
 
-async fn test_domain(domain: String, port: u16, timeout_sec: u64) -> (String, bool) {
-    let client = match create_http_client(port, timeout_sec) {
-        Ok(c) => c,
-        Err(_) => return (domain, false),
-    };
+    fn visit(
+        &self,
+        mut visitor: impl FnMut(&TestResult) -> Result<(), Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(path) = &self.stream_path else {
+            return self.results.iter().try_for_each(visitor);
+        };
 
-    let success = if try_https(&client, &domain).await.unwrap_or(false) {
-        true
-    } else {
-        try_http(&client, &domain).await.unwrap_or(false)
-    };
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut result: TestResult = serde_json::from_str(&line)
+                .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+            if let Some((domain_passes, quorum)) = &self.suite_quorum {
+                if let Some(passes) = domain_passes.get(&result.config) {
+                    result.apply_suite_quorum(passes, *quorum);
+                }
+            }
+            if let Some(direct_failed) = &self.direct_failed {
+                result.apply_direct_baseline(direct_failed);
+            }
+            visitor(&result)?;
+        }
+        Ok(())
+    }
+}
 
-    (domain, success)
+#[derive(Default)]
+struct ReportSummary {
+    ranks: Vec<ConfigRank>,
+    by_tld: HashMap<String, GroupStats>,
+    domains: DomainIndex,
+    reachable: DomainBits,
+    covered_by: Vec<(String, DomainBits)>,
+    /// Failed domains of each config, in the order of `ranks`.
+    failed_by: Vec<DomainBits>,
+    suite: Option<SuiteStats>,
 }
 
-fn create_http_client(
-    port: u16,
-    timeout_sec: u64,
-) -> Result<reqwest::Client, Box<dyn std::error::Error + Send + Sync>> {
-    let proxy = reqwest::Proxy::all(format!("socks5h://127.0.0.1:{}", port))?;
-    
-    reqwest::Client::builder()
-        .proxy(proxy)
-        .timeout(Duration::from_secs(timeout_sec))
-        .build()
-        .map_err(Into::into)
+/// Numbers the domains tested by any config, for `DomainBits`.
+#[derive(Default)]
+struct DomainIndex {
+    ids: HashMap<String, usize>,
+    names: Vec<String>,
 }
 
-async fn try_https(client: &reqwest::Client, domain: &str) -> Option<bool> {
-    client
-        .get(format!("https://{}", domain))
-        .send()
-        .await
-        .ok()
-        .map(|resp| resp.status().is_success())
-}
+impl DomainIndex {
+    fn id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
 
-async fn try_http(client: &reqwest::Client, domain: &str) -> Option<bool> {
-    client
-        .get(format!("http://{}", domain))
-        .send()
-        .await
-        .ok()
-        .map(|resp| resp.status().is_success())
-}
+    fn name(&self, id: usize) -> &str {
+        &self.names[id]
+    }
 
-async fn save_intermediate_results(
-    results: &Arc<Mutex<Vec<TestResult>>>,
-    filepath: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let locked = results.lock().await;
-    write_results_file(&locked, filepath)?;
-    print_status("[+]", &format!("Результаты сохранены в {}", filepath));
-    Ok(())
+    fn len(&self) -> usize {
+        self.names.len()
+    }
 }
 
-async fn finalize_results(
-    results: Arc<Mutex<Vec<TestResult>>>,
-    settings: &Settings,
-    group_stats: &[GroupStats],
-    session_dir: &Path,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let locked = results.lock().await;
-    write_results_file(&locked, &settings.results_file)?;
-    
-    let total_stats = calculate_total_stats(group_stats);
-    show_final_results(&total_stats, session_dir, &settings.results_file);
-    
-    Ok(())
-}
+/// A set of `DomainIndex` ids, one bit per domain, so that keeping one per
+/// config stays small when results are streamed to disk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct DomainBits(Vec<u64>);
 
-fn calculate_total_stats(group_stats: &[GroupStats]) -> GroupStats {
-    let successful: usize = group_stats.iter().map(|s| s.successful).sum();
-    let total: usize = group_stats.iter().map(|s| s.total).sum();
-    GroupStats { successful, total }
+impl DomainBits {
+    fn insert(&mut self, id: usize) {
+        let word = id / 64;
+        if self.0.len() <= word {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= 1 << (id % 64);
+    }
+
+    fn word(&self, index: usize) -> u64 {
+        self.0.get(index).copied().unwrap_or(0)
+    }
+
+    fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    fn is_subset(&self, other: &Self) -> bool {
+        self.0.iter().enumerate().all(|(i, &word)| word & !other.word(i) == 0)
+    }
+
+    /// How many of the ids are not in `other`.
+    fn count_outside(&self, other: &Self) -> usize {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, &word)| (word & !other.word(i)).count_ones() as usize)
+            .sum()
+    }
+
+    fn intersection(&self, other: &Self) -> Self {
+        Self(self.0.iter().enumerate().map(|(i, &word)| word & other.word(i)).collect())
+    }
+
+    fn union_with(&mut self, other: &Self) {
+        if self.0.len() < other.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (word, &other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+
+    fn ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(i, &word)| {
+            (0..64).filter(move |bit| word & (1 << bit) != 0).map(move |bit| i * 64 + bit)
+        })
+    }
 }
 
-fn show_welcome_message() {
-    println!();
-    print_banner("BDPI TESTER", "Инструмент тестирования прокси-конфигураций");
-    println!();
-    println!("Перед запуском убедитесь, что:");
-    println!("   ✓ Файл 'settings.toml' содержит нужные настройки");
-    println!("   ✓ Файл 'configs.txt' содержит список конфигураций");
-    println!("   ✓ Файл 'domains.txt' содержит домены для проверки");
-    println!("   ✓ Исполняемый файл ciadpi доступен в PATH");
-    println!();
+/// Per config, how many suite runs reached each domain.
+type DomainPasses = HashMap<String, HashMap<String, u32>>;
+
+/// Success rates of every config across repeated runs of the whole suite,
+/// plus how many runs each domain was reached in.
+#[derive(Default)]
+struct SuiteStats {
+    rates: Vec<(String, Vec<f32>)>,
+    domain_passes: DomainPasses,
+    domains_tested: HashMap<String, usize>,
 }
 
-fn display_startup_info(settings: &Settings, configs: &[String], domains: &[String]) {
-    println!();
-    print_section("СТАТИСТИКА ЗАГРУЗКИ");
-    print_table(&[
-        ("Конфигураций загружено:", &configs.len().to_string()),
-        ("Доменов для проверки:", &domains.len().to_string()),
-    ]);
+impl SuiteStats {
+    fn record(&mut self, summary: &ReportSummary) {
+        for rank in &summary.ranks {
+            match self.rates.iter_mut().find(|(config, _)| *config == rank.config) {
+                Some((_, rates)) => rates.push(rank.success_rate),
+                None => self.rates.push((rank.config.clone(), vec![rank.success_rate])),
+            }
+            let tested = self.domains_tested.entry(rank.config.clone()).or_default();
+            *tested = (*tested).max(rank.total());
+        }
+        for (config, domains) in &summary.covered_by {
+            let passes = self.domain_passes.entry(config.clone()).or_default();
+            for id in domains.ids() {
+                *passes.entry(summary.domains.name(id).to_string()).or_default() += 1;
+            }
+        }
+    }
 
-    print_section("НАСТРОЙКИ");
-    print_table(&[
-        ("Размер группы:", &settings.group_size.to_string()),
-        ("Стартовый порт:", &settings.start_port.to_string()),
-        ("Задержка между группами:", &format!("{} мс", settings.group_delay_ms)),
-        ("Таймаут запроса:", &format!("{} сек", settings.request_timeout_sec)),
-        ("Папка логов:", &settings.log_dir),
-        ("Файл результатов:", &settings.results_file),
-    ]);
+    /// Domains the config reached in at least `quorum` runs, out of all it tested.
+    fn quorum_reached(&self, config: &str, quorum: u32) -> (usize, usize) {
+        let reached = self
+            .domain_passes
+            .get(config)
+            .map_or(0, |passes| passes.values().filter(|&&runs| runs >= quorum).count());
+        (reached, self.domains_tested.get(config).copied().unwrap_or(0))
+    }
+
+    fn runs(&self) -> usize {
+        self.rates.iter().map(|(_, rates)| rates.len()).max().unwrap_or(0)
+    }
 }
 
-fn print_group_header(group_num: usize, total_groups: usize, config_count: usize, start_port: u16) {
-    print_section(&format!("ГРУППА {}/{}", group_num, total_groups));
-    println!("   Конфигураций в группе: {}", config_count);
-    println!("   Порт диапазон: {}-{}", start_port, start_port + config_count as u16 - 1);
+fn mean_and_variance(values: &[f32]) -> (f32, f32) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+    let n = values.len() as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+    (mean, variance)
 }
 
-fn print_config_start(config: &str, port: u16) {
-    let config_name = extract_config_name(config);
-    print_status("[~]", &format!("Запускаем {} на порту {}...", config_name, port));
+impl ReportSummary {
+    fn collect(store: &ResultStore) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut summary = Self::default();
+        store.visit(|result| {
+            summary.add(result);
+            Ok(())
+        })?;
+        Ok(summary)
+    }
+
+    fn add(&mut self, result: &TestResult) {
+        let mut reached = DomainBits::default();
+        for domain in &result.successful_domains {
+            let stats = self.by_tld.entry(domain_tld(domain)).or_default();
+            stats.successful += 1;
+            stats.total += 1;
+            reached.insert(self.domains.id(domain));
+        }
+        let mut failed = DomainBits::default();
+        for domain in &result.failed_domains {
+            self.by_tld.entry(domain_tld(domain)).or_default().total += 1;
+            failed.insert(self.domains.id(domain));
+        }
+        for domain in &result.excluded_domains {
+            self.domains.id(domain);
+        }
+        self.reachable.union_with(&reached);
+        self.covered_by.push((result.config.clone(), reached));
+        self.failed_by.push(failed);
+        self.ranks.push(result.rank());
+    }
+
+    /// Greedy set cover: repeatedly picks the config that reaches the most
+    /// domains not yet reached by the configs picked before it.
+    fn covering_set(&self) -> Vec<(&str, usize)> {
+        let mut covered = DomainBits::default();
+        let mut picked = Vec::new();
+        loop {
+            let best = self
+                .covered_by
+                .iter()
+                // Reversed so that ties go to the config tested first.
+                .rev()
+                .map(|(config, domains)| (config, domains, domains.count_outside(&covered)))
+                .max_by_key(|(_, _, new)| *new);
+
+            match best {
+                Some((config, domains, new)) if new > 0 => {
+                    covered.union_with(domains);
+                    picked.push((config.as_str(), new));
+                }
+                _ => return picked,
+            }
+        }
+    }
+
+    /// Pairs `(dominated, dominating)`: the first config reaches no domain the
+    /// second doesn't and isn't faster by median latency. Configs that reached
+    /// nothing are left out. Among equal configs, the one tested first stays.
+    fn dominated(&self) -> Vec<(&ConfigRank, &ConfigRank, usize, usize)> {
+        let entries: Vec<(&ConfigRank, &DomainBits)> = self
+            .ranks
+            .iter()
+            .zip(&self.covered_by)
+            .map(|(rank, (_, domains))| (rank, domains))
+            .filter(|(_, domains)| !domains.is_empty())
+            .collect();
+        let latency = |rank: &ConfigRank| rank.median_latency_ms.unwrap_or(u64::MAX);
+
+        let mut dominated = Vec::new();
+        for (i, &(rank, domains)) in entries.iter().enumerate() {
+            let dominator = entries
+                .iter()
+                .enumerate()
+                // Reversed so that ties go to the config tested first, the one that stays.
+                .rev()
+                .filter(|&(j, &(other, other_domains))| {
+                    let equal = other_domains.len() == domains.len() && latency(other) == latency(rank);
+                    j != i
+                        && domains.is_subset(other_domains)
+                        && latency(rank) >= latency(other)
+                        && (!equal || j < i)
+                })
+                .map(|(_, entry)| *entry)
+                .max_by(|(a, a_domains), (b, b_domains)| {
+                    a_domains
+                        .len()
+                        .cmp(&b_domains.len())
+                        .then_with(|| latency(b).cmp(&latency(a)))
+                });
+            if let Some((other, other_domains)) = dominator {
+                dominated.push((rank, other, domains.len(), other_domains.len()));
+            }
+        }
+        dominated
+    }
+
+    fn coverage(&self) -> (usize, usize) {
+        (self.reachable.len(), self.domains.len())
+    }
+
+    fn perfect_configs(&self) -> usize {
+        self.ranks
+            .iter()
+            .filter(|rank| rank.total() > 0 && rank.failed == 0)
+            .count()
+    }
+
+    fn best_success_rate(&self) -> f32 {
+        self.ranks.iter().map(|rank| rank.success_rate).fold(0.0, f32::max)
+    }
+
+    fn recommended(&self, settings: &Settings) -> Option<Vec<&ConfigRank>> {
+        let thresholds = settings.recommend_thresholds?;
+        let mut recommended: Vec<&ConfigRank> =
+            self.ranks.iter().filter(|rank| rank.is_recommended(thresholds)).collect();
+        recommended.sort_by(|a, b| {
+            b.success_rate
+                .partial_cmp(&a.success_rate)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.median_latency_ms.cmp(&b.median_latency_ms))
+        });
+        Some(recommended)
+    }
 }
 
-fn print_config_result(config_name: &str, successful: usize, total: usize) {
-    let rate = (successful as f32 / total as f32 * 100.0) as u32;
-    let status = match rate {
-        90..=100 => "[OK]",
-        50..=89 => "[WARN]",
-        _ => "[FAIL]",
-    };
-    
-    println!("   {} {}: {}/{} успешно ({}%)", status, config_name, successful, total, rate);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FailureKind {
+    Timeout,
+    Connect,
+    Rejected,
+    ProxyAuth,
+    Hung,
+    Other,
 }
 
-fn print_config_error(config: &str) {
-    println!("   [FAIL] {}: завершился с ошибкой", extract_config_name(config));
+impl FailureKind {
+    fn from_error(error: &reqwest::Error) -> Self {
+        if error.is_timeout() {
+            FailureKind::Timeout
+        } else if is_socks_handshake_error(error) {
+            FailureKind::ProxyAuth
+        } else if error.is_connect() {
+            FailureKind::Connect
+        } else {
+            FailureKind::Other
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            FailureKind::Timeout => "timeout",
+            FailureKind::Connect => "connection error",
+            FailureKind::Rejected => "rejected status",
+            FailureKind::ProxyAuth => "proxy auth failed",
+            FailureKind::Hung => "hung",
+            FailureKind::Other => "other error",
+        }
+    }
 }
 
-fn print_group_summary(group_num: usize, stats: &GroupStats) {
-    let rate = stats.success_rate() as u32;
-    println!();
-    println!("   Группа {} завершена: {}/{} успешно ({}%)", 
-        group_num, stats.successful, stats.total, rate);
+/// reqwest turns tokio-socks errors into `socks connect error: <message>`
+/// strings, so the variant can only be told by its message.
+fn is_socks_handshake_error(error: &reqwest::Error) -> bool {
+    const HANDSHAKE_ERRORS: &[&str] = &[
+        "Invalid response version",
+        "No acceptable auth methods",
+        "Unknown auth method",
+        "Invalid auth values",
+        "Password auth failure",
+        "Authorization required",
+    ];
+
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(err) = source {
+        let message = err.to_string();
+        if message.contains("socks connect error")
+            && HANDSHAKE_ERRORS.iter().any(|known| message.contains(known))
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
 }
 
-fn show_final_results(stats: &GroupStats, session_dir: &Path, results_file: &str) {
-    println!();
-    print_section("ТЕСТИРОВАНИЕ ЗАВЕРШЕНО");
-    println!();
-    
-    println!("   Общая статистика:");
-    print_table(&[
-        ("Всего тестов:", &stats.total.to_string()),
-        ("Успешных:", &stats.successful.to_string()),
-        ("Процент успеха:", &format!("{:.1}%", stats.success_rate())),
-    ]);
-    
-    println!("   Результаты сохранены:");
-    print_table(&[
-        ("Файл результатов:", results_file),
-        ("Папка логов:", &session_dir.display().to_string()),
-    ]);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    Http,
+    Https,
 }
 
-fn print_banner(title: &str, subtitle: &str) {
-    const WIDTH: usize = 60;
-    println!("┌{}┐", "─".repeat(WIDTH));
-    println!("│{:^WIDTH$}│", title);
-    println!("│{:^WIDTH$}│", subtitle);
-    println!("└{}┘", "─".repeat(WIDTH));
+impl Scheme {
+    fn default_port(self) -> u16 {
+        match self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Scheme::Http => "http://",
+            Scheme::Https => "https://",
+        }
+    }
 }
 
-fn print_section(title: &str) {
-    println!();
-    println!("▶ {}", title);
-    println!("  {}", "─".repeat(58));
+#[derive(Debug, Clone)]
+struct DomainSpec {
+    line: String,
+    name: String,
+    host: String,
+    port: Option<u16>,
+    path: String,
+    https_path: Option<String>,
+    http_path: Option<String>,
+    scheme: Option<Scheme>,
+    sni: Option<String>,
+    expect_status: Option<u16>,
+    method: reqwest::Method,
+    body: Option<String>,
+    content_type: String,
 }
 
-fn print_table(rows: &[(&str, &str)]) {
-    let max_left = rows.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
-    
-    for (left, right) in rows {
-        println!("   {:width$} │ {}", left, right, width = max_left);
+impl DomainSpec {
+    fn parse(line: &str) -> Result<Self, String> {
+        // The body may contain spaces, so it always takes the rest of the line.
+        let (options, body) = match line.find("body=") {
+            Some(idx) if idx > 0 && line[..idx].ends_with(|c: char| c.is_whitespace() || c == '|') => {
+                (&line[..idx], Some(line[idx + "body=".len()..].trim().to_string()))
+            }
+            _ => (line, None),
+        };
+        let mut parts = options
+            .split(|c: char| c.is_whitespace() || c == '|')
+            .filter(|part| !part.is_empty())
+            .peekable();
+
+        let method = match parts.peek() {
+            Some(token) if token.chars().all(|c| c.is_ascii_uppercase()) => {
+                let method = reqwest::Method::from_bytes(token.as_bytes())
+                    .map_err(|_| format!("Invalid method '{}' in '{}'", token, line))?;
+                parts.next();
+                method
+            }
+            _ => reqwest::Method::GET,
+        };
+        let target = parts.next().ok_or("Empty domain line")?;
+        let (scheme, name) = if let Some(rest) = target.strip_prefix("https://") {
+            (Some(Scheme::Https), rest)
+        } else if let Some(rest) = target.strip_prefix("http://") {
+            (Some(Scheme::Http), rest)
+        } else {
+            (None, target)
+        };
+
+        let name = name.trim_end_matches('/');
+        let (authority, path) = match name.find('/') {
+            Some(idx) => (&name[..idx], &name[idx..]),
+            None => (name, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse::<u16>()
+                    .ok()
+                    .filter(|&p| p != 0)
+                    .ok_or_else(|| format!("Invalid port '{}' in '{}'", port, line))?;
+                (host, Some(port))
+            }
+            None => (authority, None),
+        };
+
+        let mut spec = Self {
+            line: line.to_string(),
+            name: name.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            https_path: None,
+            http_path: None,
+            scheme,
+            sni: None,
+            expect_status: None,
+            method,
+            body,
+            content_type: "application/json".to_string(),
+        };
+
+        for option in parts {
+            match option.split_once('=') {
+                Some(("sni", value)) if !value.is_empty() => spec.sni = Some(value.to_string()),
+                Some(("expect", value)) => {
+                    let status = value
+                        .parse::<u16>()
+                        .ok()
+                        .filter(|status| (100..=599).contains(status))
+                        .ok_or_else(|| format!("Invalid expected status '{}' in '{}'", value, line))?;
+                    spec.expect_status = Some(status);
+                }
+                Some(("content_type", value)) if !value.is_empty() => spec.content_type = value.to_string(),
+                Some(("https_path", value)) if value.starts_with('/') => spec.https_path = Some(value.to_string()),
+                Some(("http_path", value)) if value.starts_with('/') => spec.http_path = Some(value.to_string()),
+                _ => return Err(format!("Unknown domain option '{}' in '{}'", option, line)),
+            }
+        }
+
+        Ok(spec)
+    }
+
+    fn connect_host(&self) -> &str {
+        self.sni.as_deref().unwrap_or(&self.host)
+    }
+
+    /// `host[:port]` the request is meant for, without the fronting SNI.
+    fn authority(&self) -> String {
+        match self.port {
+            Some(port) => format!("{}:{}", self.host, port),
+            None => self.host.clone(),
+        }
+    }
+
+    fn url(&self, scheme: Scheme) -> String {
+        let host = match scheme {
+            Scheme::Https => self.connect_host(),
+            Scheme::Http => &self.host,
+        };
+        let path = match scheme {
+            Scheme::Https => self.https_path.as_deref(),
+            Scheme::Http => self.http_path.as_deref(),
+        }
+        .unwrap_or(&self.path);
+        match self.port {
+            Some(port) => format!("{}{}:{}{}", scheme.prefix(), host, port, path),
+            None => format!("{}{}{}", scheme.prefix(), host, path),
+        }
+    }
+
+    /// The same target with `www.` added or removed; domain fronting entries have none.
+    fn www_variant(&self) -> Option<Self> {
+        if self.sni.is_some() || self.host.parse::<std::net::IpAddr>().is_ok() {
+            return None;
+        }
+        let host = match self.host.strip_prefix("www.") {
+            Some(bare) => bare.to_string(),
+            None => format!("www.{}", self.host),
+        };
+        Some(Self { host, ..self.clone() })
+    }
+
+    fn request(&self, client: &reqwest::Client, scheme: Scheme) -> reqwest::RequestBuilder {
+        let mut request = client.request(self.method.clone(), self.url(scheme));
+        if scheme == Scheme::Https && self.sni.is_some() {
+            request = request.header(reqwest::header::HOST, self.authority());
+        }
+        if let Some(body) = &self.body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, &self.content_type)
+                .body(body.clone());
+        }
+        request
     }
-    println!();
 }
 
-fn print_status(prefix: &str, message: &str) {
-    println!("   {} {}", prefix, message);
+struct DomainOutcome {
+    domain: String,
+    failure: Option<FailureKind>,
+    bytes: u64,
+    attempts: usize,
+    successes: usize,
+    latencies_ms: Vec<u64>,
+    www_variant: bool,
+    resolved_ip: Option<std::net::IpAddr>,
+    /// Whether an HTTP/3 request got through; `None` when none was attempted.
+    http3: Option<bool>,
 }
 
-fn wait_for_start() {
-    print_status("[?]", "Для начала работы введите 'start' и нажмите Enter:");
-    wait_for_input("start", "Пожалуйста, введите 'start' для продолжения:");
-    println!();
+impl DomainOutcome {
+    fn from_result(domain: String, result: Result<u64, FailureKind>, latency: Duration) -> Self {
+        let successes = usize::from(result.is_ok());
+        let latencies_ms = if result.is_ok() {
+            vec![latency.as_millis() as u64]
+        } else {
+            Vec::new()
+        };
+        Self {
+            domain,
+            failure: result.err(),
+            bytes: result.unwrap_or(0),
+            attempts: 1,
+            successes,
+            latencies_ms,
+            www_variant: false,
+            resolved_ip: None,
+            http3: None,
+        }
+    }
 }
 
-fn confirm_start() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("   Нажмите Enter для начала или Ctrl+C для отмены...");
-    let mut input = String::new();
-    stdin().read_line(&mut input)?;
-    Ok(())
+#[derive(Debug, Default)]
+struct DomainResults {
+    successful: Vec<String>,
+    failed: Vec<String>,
+    excluded: Vec<String>,
+    failure_reasons: HashMap<String, FailureKind>,
+    bytes_received: u64,
+    attempts_successful: usize,
+    attempts_total: usize,
+    latencies_ms: Vec<u64>,
+    www_variant: Vec<String>,
+    resolved_ips: HashMap<String, String>,
+    http3_successful: Vec<String>,
+    http3_failed: Vec<String>,
 }
 
-fn wait_for_quit() {
-    print_status("[?]", "Для выхода введите 'quit' и нажмите Enter:");
-    wait_for_input("quit", "Пожалуйста, введите 'quit' для выхода:");
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionSummary {
+    configs: usize,
+    successful: usize,
+    total: usize,
+    #[serde(default)]
+    bytes_received: u64,
+    #[serde(default)]
+    configs_perfect: usize,
+    #[serde(default)]
+    best_success_rate: f32,
+    #[serde(default)]
+    socket_options: SocketOptions,
+    #[serde(default)]
+    tls_fingerprint: TlsFingerprint,
+    #[serde(default)]
+    groups: Vec<GroupTiming>,
 }
 
-fn wait_for_input(expected: &str, retry_message: &str) {
-    loop {
-        let mut input = String::new();
-        if stdin().read_line(&mut input).is_ok() && input.trim().eq_ignore_ascii_case(expected) {
-            break;
+/// Socket options the domain test client was built with.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+struct SocketOptions {
+    tcp_nodelay: bool,
+    tcp_keepalive_sec: Option<u64>,
+}
+
+impl SocketOptions {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            tcp_nodelay: settings.tcp_nodelay,
+            tcp_keepalive_sec: settings.tcp_keepalive_sec.filter(|&sec| sec > 0),
         }
-        print_status("[ERROR]", retry_message);
+    }
+
+    fn describe(&self) -> String {
+        let keepalive = match self.tcp_keepalive_sec {
+            Some(sec) => format!("TCP keepalive {} сек", sec),
+            None => "TCP keepalive выкл".to_string(),
+        };
+        let nodelay = if self.tcp_nodelay { "вкл" } else { "выкл" };
+        format!("TCP_NODELAY {}, {}", nodelay, keepalive)
     }
 }
 
-async fn wait_between_groups(delay_ms: u64) {
-    println!();
-    print_status("[~]", &format!("Ожидание {} мс перед следующей группой...", delay_ms));
-    time::sleep(Duration::from_millis(delay_ms)).await;
+#[derive(Debug, Serialize, Deserialize)]
+struct GroupTiming {
+    group: usize,
+    started_sec: f64,
+    finished_sec: f64,
 }
 
-fn sanitize_filename(config: &str, port: u16) -> String {
-    let base = config
-        .split_whitespace()
-        .next()
-        .unwrap_or("cfg")
-        .chars()
-        .take(20)
-        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
-        .collect::<String>();
-    
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0);
-    
-    format!("{}_p{}_t{}", base, port, timestamp)
+struct ConfigSummary {
+    name: String,
+    successful: usize,
+    total: usize,
+    bytes_received: u64,
+    process_exit: ProcessExit,
 }
 
-fn extract_config_name(config: &str) -> String {
-    config
-        .split_whitespace()
-        .next()
-        .unwrap_or("unknown")
-        .to_string()
+#[derive(Default)]
+enum CliCommand {
+    #[default]
+    Run,
+    ListSessions,
+    PrintSchema,
+    Spot {
+        config: String,
+        domain: String,
+    },
+    Stability {
+        config: String,
+        domain: String,
+        duration: Duration,
+    },
+    ExternalProxy {
+        port: u16,
+    },
+    Bisect {
+        config: String,
+    },
+    CleanLogs,
 }
 
-fn write_results_file(
-    results: &[TestResult],
-    filepath: &str,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(filepath)?;
+#[derive(Default)]
+struct CliArgs {
+    command: CliCommand,
+    domain_filter: Option<String>,
+    reorder_configs: bool,
+    max_groups: Option<usize>,
+    baseline_config: Option<String>,
+    manifest: Option<String>,
+    defaults_on_error: bool,
+    shortlist: Option<String>,
+    stream_json: bool,
+    output_dir: Option<String>,
+    strict: bool,
+    export_top: Option<String>,
+    profile: Option<String>,
+    fuzz: Option<(String, usize)>,
+    older_than_days: Option<u64>,
+    keep_last: Option<usize>,
+    dry_run: bool,
+    watch: Option<Duration>,
+    start_port: Option<u16>,
+    no_progress: bool,
+}
 
-    write_header(&mut file, results.len())?;
-    write_top_configs(&mut file, results)?;
-    write_detailed_results(&mut file, results)?;
-    
-    file.flush()?;
-    Ok(())
+#[derive(Deserialize)]
+struct Manifest {
+    settings: Settings,
+    configs: Vec<String>,
+    domains: Vec<String>,
 }
 
-fn write_header(
+struct RunInput {
+    settings: Settings,
+    configs: Vec<String>,
+    domains: Vec<DomainSpec>,
+}
+
+#[derive(Default)]
+struct GroupStats {
+    successful: usize,
+    total: usize,
+    bytes_received: u64,
+    aborted: bool,
+    process_exits: Vec<(String, ProcessExit)>,
+    started_sec: f64,
+    finished_sec: f64,
+}
+
+#[derive(Debug, Clone)]
+enum ProcessExit {
+    Killed,
+    Exited,
+    Crashed(String),
+}
+
+impl ProcessExit {
+    fn describe(&self) -> String {
+        match self {
+            ProcessExit::Killed => "остановлен программой".to_string(),
+            ProcessExit::Exited => "завершился сам (код 0)".to_string(),
+            ProcessExit::Crashed(status) => format!("аварийно завершился ({})", status),
+        }
+    }
+}
+
+struct GroupContext {
+    limiter: Arc<Semaphore>,
+    counters: LiveCounters,
+    config_count: usize,
+    spawn_failures: AtomicUsize,
+    abort_threshold: Option<f32>,
+    reserve_ports: std::sync::Mutex<Vec<u16>>,
+    port_tracker: Arc<PortTracker>,
+    progress: MultiProgress,
+}
+
+/// Session-wide record of ciadpi port binds, used to spot ports that are
+/// bound again before the OS has fully released them.
+#[derive(Default)]
+struct PortTracker {
+    state: std::sync::Mutex<PortTrackerState>,
+}
+
+#[derive(Default)]
+struct PortTrackerState {
+    binds: HashMap<u16, u32>,
+    released_at: HashMap<u16, std::time::Instant>,
+    fast_rebinds: Vec<(u16, Duration)>,
+    fast_rebinds_total: usize,
+}
+
+/// Running success/total tallies, updated as each domain result arrives so
+/// they can be shown live under the group's progress bars.
+#[derive(Default)]
+struct LiveCounters {
+    successful: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl LiveCounters {
+    fn record(&self, success: bool) {
+        if success {
+            self.successful.fetch_add(1, Ordering::SeqCst);
+        }
+        self.total.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn retract(&self, successful: usize, total: usize) {
+        self.successful.fetch_sub(successful, Ordering::SeqCst);
+        self.total.fetch_sub(total, Ordering::SeqCst);
+    }
+
+    fn snapshot(&self) -> (usize, usize) {
+        (self.successful.load(Ordering::SeqCst), self.total.load(Ordering::SeqCst))
+    }
+}
+
+impl PortTracker {
+    fn bind(&self, port: u16, min_interval: Duration) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        *state.binds.entry(port).or_default() += 1;
+        let gap = state.released_at.get(&port).map(|released| released.elapsed());
+        if let Some(gap) = gap.filter(|&gap| gap < min_interval) {
+            state.fast_rebinds.push((port, gap));
+            state.fast_rebinds_total += 1;
+        }
+    }
+
+    fn release(&self, port: u16) {
+        if let Ok(mut state) = self.state.lock() {
+            state.released_at.insert(port, std::time::Instant::now());
+        }
+    }
+
+    /// Fast rebinds since the previous call.
+    fn take_fast_rebinds(&self) -> Vec<(u16, Duration)> {
+        self.state
+            .lock()
+            .map(|mut state| std::mem::take(&mut state.fast_rebinds))
+            .unwrap_or_default()
+    }
+
+    /// Total fast rebinds and the most frequently bound port with its bind count.
+    fn summary(&self) -> (usize, Option<(u16, u32)>) {
+        let Ok(state) = self.state.lock() else {
+            return (0, None);
+        };
+        let busiest = state
+            .binds
+            .iter()
+            .max_by_key(|&(&port, &binds)| (binds, std::cmp::Reverse(port)))
+            .map(|(&port, &binds)| (port, binds));
+        (state.fast_rebinds_total, busiest)
+    }
+}
+
+impl GroupContext {
+    fn record_spawn_failure(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Next port for a retry after a bind failure: from `reserve_ports` while
+    /// it lasts, otherwise any port the OS reports as free.
+    fn take_reserve_port(&self) -> Option<u16> {
+        if let Some(port) = self.reserve_ports.lock().ok()?.pop() {
+            return Some(port);
+        }
+        std::net::TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.local_addr())
+            .map(|addr| addr.port())
+            .ok()
+    }
+
+    fn should_abort(&self) -> bool {
+        let Some(threshold) = self.abort_threshold else {
+            return false;
+        };
+        let failures = self.spawn_failures.load(Ordering::SeqCst);
+        self.config_count > 0 && failures as f32 / self.config_count as f32 > threshold
+    }
+}
+
+impl GroupStats {
+    fn success_rate(&self) -> f32 {
+        if self.total > 0 {
+            (self.successful as f32 / self.total as f32) * 100.0
+        } else {
+            0.0
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .on_thread_start(pin_current_thread)
+        .on_thread_unpark(pin_current_thread)
+        .build()?
+        .block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = parse_args()?;
+    HUMAN_OUTPUT_TO_STDERR.store(cli.stream_json, Ordering::Relaxed);
+    PROGRESS_BARS.store(
+        !cli.no_progress && !cli.stream_json && std::io::stdout().is_terminal(),
+        Ordering::Relaxed,
+    );
+    match cli.command {
+        CliCommand::Run => {}
+        CliCommand::ListSessions => {
+            let settings = load_cli_settings(&cli)?;
+            return list_sessions(&settings.log_dir);
+        }
+        CliCommand::PrintSchema => {
+            println!("{}", serde_json::to_string_pretty(&results_schema())?);
+            return Ok(());
+        }
+        CliCommand::Spot { ref config, ref domain } => {
+            let settings = load_cli_settings(&cli)?;
+            return run_spot_check(config, domain, &settings).await;
+        }
+        CliCommand::Stability { ref config, ref domain, duration } => {
+            let settings = load_cli_settings(&cli)?;
+            return run_stability_check(config, domain, duration, &settings).await;
+        }
+        CliCommand::ExternalProxy { port } => {
+            let RunInput { settings, mut domains, .. } = load_cli_input(&cli)?;
+            if let Some(pattern) = &cli.domain_filter {
+                domains = filter_domains(domains, pattern)?;
+            }
+            return run_external_proxy(port, &domains, &settings).await;
+        }
+        CliCommand::Bisect { ref config } => {
+            let RunInput { settings, mut domains, .. } = load_cli_input(&cli)?;
+            if let Some(pattern) = &cli.domain_filter {
+                domains = filter_domains(domains, pattern)?;
+            }
+            return run_bisect(config, &domains, &settings).await;
+        }
+        CliCommand::CleanLogs => {
+            let settings = load_cli_settings(&cli)?;
+            return clean_logs(&settings.log_dir, cli.older_than_days, cli.keep_last, cli.dry_run);
+        }
+    }
+
+    show_welcome_message();
+    wait_for_start();
+
+    let RunInput { mut settings, configs, mut domains } = load_cli_input(&cli)?;
+    if cli.max_groups.is_some() {
+        settings.max_groups = cli.max_groups;
+    }
+    settings.stream_json = cli.stream_json;
+    if settings.max_groups == Some(0) {
+        return Err("max_groups must be at least 1".into());
+    }
+    if !(0.0..=1.0).contains(&settings.connections_success_fraction) {
+        return Err("connections_success_fraction must be between 0 and 1".into());
+    }
+    if let Some(quorum) = settings.domain_success_quorum {
+        validate_domain_success_quorum(quorum, &settings)?;
+    }
+    validate_tls_fingerprint(&settings)?;
+    validate_http3(&settings)?;
+    if let Some(value) = cli.baseline_config.clone().or(settings.baseline_config.take()) {
+        settings.baseline_config = Some(resolve_baseline_config(&value, &configs)?);
+    }
+    if settings.tier_thresholds.is_some_and(|t| t.good > t.excellent) {
+        return Err("tier_thresholds.good must not exceed tier_thresholds.excellent".into());
+    }
+    if let Some(control) = &settings.control_domain {
+        DomainSpec::parse(control).map_err(|e| format!("Invalid control_domain: {}", e))?;
+    }
+    if let Some(check) = &settings.persistent_check {
+        DomainSpec::parse(&check.target).map_err(|e| format!("Invalid persistent_check.target: {}", e))?;
+    }
+    if cli.reorder_configs && cli.manifest.is_some() {
+        return Err("--reorder-configs cannot be used with --manifest".into());
+    }
+    if cli.reorder_configs && cli.shortlist.is_some() {
+        return Err("--reorder-configs cannot be used with --shortlist".into());
+    }
+    if cli.reorder_configs && cli.fuzz.is_some() {
+        return Err("--reorder-configs cannot be used with --fuzz".into());
+    }
+
+    if let Some(pattern) = &cli.domain_filter {
+        domains = filter_domains(domains, pattern)?;
+    }
+
+    if domains.len() < settings.min_domains_warning {
+        if cli.strict {
+            return Err(format!(
+                "Only {} domains loaded, min_domains_warning is {}",
+                domains.len(),
+                settings.min_domains_warning
+            )
+            .into());
+        }
+        print_section("ВНИМАНИЕ: МАЛО ДОМЕНОВ");
+        print_status(
+            "[WARN]",
+            &format!(
+                "Загружено доменов: {} (меньше {}). Процент успеха будет статистически ненадёжным - проверьте, не обрезан ли domains.txt",
+                domains.len(),
+                settings.min_domains_warning
+            ),
+        );
+        console!();
+    }
+
+    validate_port_pool(&settings, configs.len())?;
+    if !settings.fixed_ciadpi_args.iter().any(|arg| arg.contains("{port}")) {
+        print_status(
+            "[WARN]",
+            "fixed_ciadpi_args не содержит {port}: ciadpi не узнает, на каком порту запускаться",
+        );
+    }
+
+    configure_cpu_affinity(&settings.cpu_affinity);
+    display_startup_info(&settings, &configs, &domains);
+    check_ciadpi_compatibility(&configs, &settings).await;
+    confirm_start()?;
+
+    let mut previous: Option<RunRecord> = None;
+    let mut post_hook = None;
+    loop {
+        let run_started = std::time::Instant::now();
+        mark_run_started();
+        match run_suite(&cli, settings.clone(), &configs, &domains).await {
+            Ok(record) => {
+                if let Some(previous) = &previous {
+                    show_watch_delta(previous, &record);
+                }
+                post_hook = run_post_hook(&settings, &record, previous.as_ref()).or(post_hook);
+                if cli.watch.is_some() {
+                    append_run_history(&settings.watch_history_file, &record)?;
+                    previous = Some(record);
+                }
+            }
+            // A busy port or a crashed ciadpi must not end a watch session.
+            Err(e) if cli.watch.is_some() => {
+                print_status("[ERROR]", &format!("Прогон завершился с ошибкой: {}", e));
+                append_run_history(&settings.watch_history_file, &FailedRun::new(e.to_string()))?;
+            }
+            Err(e) => return Err(e),
+        }
+        let Some(interval) = cli.watch else {
+            break;
+        };
+
+        let wait = interval.saturating_sub(run_started.elapsed());
+        print_status(
+            "[~]",
+            &format!("Режим наблюдения: следующий прогон через {} (Ctrl+C для выхода)", format_duration(wait)),
+        );
+        time::sleep(wait).await;
+    }
+
+    if let Some(hook) = post_hook {
+        let _ = hook.await;
+    }
+    if !settings.exit_when_done {
+        wait_for_quit();
+    }
+
+    Ok(())
+}
+
+/// One full pass over all configs: session directory, groups, report files.
+async fn run_suite(
+    cli: &CliArgs,
+    mut settings: Settings,
+    configs: &[String],
+    domains: &[DomainSpec],
+) -> Result<RunRecord, Box<dyn std::error::Error + Send + Sync>> {
+    let direct_failed = if settings.direct_baseline && !settings.direct_baseline_parallel {
+        Some(run_direct_baseline(domains, &settings).await)
+    } else {
+        None
+    };
+
+    let session_dir = match create_session_directory(&settings.log_dir) {
+        Ok(dir) => Some(dir),
+        Err(e) if settings.logs_optional => {
+            print_status("[WARN]", &format!("Не удалось создать папку логов ({}), логи ciadpi отключены", e));
+            None
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(dir) = &session_dir {
+        if settings.results_file_per_session {
+            settings.results_file = session_results_path(dir, &settings.results_file);
+        }
+        write_session_snapshot(dir, &settings, configs, domains)?;
+    }
+
+    let suite_repeats = settings.suite_repeats.max(1);
+    let mut suite = (suite_repeats > 1).then(SuiteStats::default);
+    let mut group_stats = Vec::new();
+    let mut results = Arc::new(Mutex::new(ResultStore::new(&settings)?));
+
+    // The direct pass uses no ciadpi ports, so it can overlap the first group.
+    let direct_task = (settings.direct_baseline && settings.direct_baseline_parallel).then(|| {
+        let (domains, settings) = (domains.to_vec(), settings.clone());
+        tokio::spawn(async move { run_direct_baseline(&domains, &settings).await })
+    });
+
+    for run in 1..=suite_repeats {
+        if suite_repeats > 1 {
+            print_section(&format!("ПРОГОН {}/{}", run, suite_repeats));
+            if run > 1 {
+                results = Arc::new(Mutex::new(ResultStore::new(&settings)?));
+            }
+        }
+
+        group_stats.extend(
+            run_all_groups(configs, domains, &settings, session_dir.as_deref(), results.clone()).await?,
+        );
+
+        if let Some(suite) = &mut suite {
+            suite.record(&ReportSummary::collect(&*results.lock().await)?);
+        }
+    }
+
+    let quorum = settings.domain_success_quorum.and_then(|quorum| quorum.runs());
+    if let (Some(suite), Some(quorum)) = (&suite, quorum) {
+        results.lock().await.set_suite_quorum(suite.domain_passes.clone(), quorum);
+    }
+
+    let direct_failed = match direct_task {
+        Some(task) => Some(task.await.map_err(|e| format!("Direct baseline task failed: {}", e))?),
+        None => direct_failed,
+    };
+    if let Some(direct_failed) = direct_failed {
+        results.lock().await.set_direct_baseline(direct_failed);
+    }
+
+    let mut summary = ReportSummary::collect(&*results.lock().await)?;
+    summary.suite = suite;
+
+    if cli.reorder_configs {
+        reorder_configs_file("configs.txt", &summary.ranks)?;
+    }
+
+    if let Some(path) = &cli.export_top {
+        export_top_configs(path, &summary.ranks, settings.top_min_success_rate)?;
+    }
+
+    finalize_results(results, summary, &settings, &group_stats, session_dir.as_deref()).await
+}
+
+fn parse_args() -> Result<CliArgs, Box<dyn std::error::Error + Send + Sync>> {
+    let mut cli = CliArgs::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "list-sessions" | "--list-sessions" => cli.command = CliCommand::ListSessions,
+            "--print-schema" => cli.command = CliCommand::PrintSchema,
+            "clean-logs" => cli.command = CliCommand::CleanLogs,
+            "--older-than" => {
+                let value = next_arg_value(&mut args, &arg)?;
+                let days = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --older-than: {}", value))?;
+                cli.older_than_days = Some(days);
+            }
+            "--keep-last" => {
+                let value = next_arg_value(&mut args, &arg)?;
+                let keep = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --keep-last: {}", value))?;
+                cli.keep_last = Some(keep);
+            }
+            "--dry-run" => cli.dry_run = true,
+            "--no-progress" => cli.no_progress = true,
+            "--start-port" => {
+                let value = next_arg_value(&mut args, &arg)?;
+                let port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --start-port: {}", value))?;
+                cli.start_port = Some(port);
+            }
+            "--watch" => {
+                let interval = parse_duration_arg(&next_arg_value(&mut args, &arg)?)?;
+                if interval.is_zero() {
+                    return Err("--watch interval must be above zero".into());
+                }
+                cli.watch = Some(interval);
+            }
+            "spot" => {
+                let config = next_arg_value(&mut args, &arg)?;
+                let domain = next_arg_value(&mut args, &arg)?;
+                cli.command = CliCommand::Spot { config, domain };
+            }
+            "--stability" => {
+                let config = next_arg_value(&mut args, &arg)?;
+                let domain = next_arg_value(&mut args, &arg)?;
+                let duration = parse_duration_arg(&next_arg_value(&mut args, &arg)?)?;
+                cli.command = CliCommand::Stability { config, domain, duration };
+            }
+            "--external-proxy" => {
+                let value = next_arg_value(&mut args, &arg)?;
+                let port = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --external-proxy: {}", value))?;
+                cli.command = CliCommand::ExternalProxy { port };
+            }
+            "--bisect" => {
+                let config = next_arg_value(&mut args, &arg)?;
+                cli.command = CliCommand::Bisect { config };
+            }
+            "--domain-filter" => cli.domain_filter = Some(next_arg_value(&mut args, &arg)?),
+            "--reorder-configs" => cli.reorder_configs = true,
+            "--max-groups" => {
+                let value = next_arg_value(&mut args, &arg)?;
+                let max_groups = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for --max-groups: {}", value))?;
+                cli.max_groups = Some(max_groups);
+            }
+            "--baseline-config" => cli.baseline_config = Some(next_arg_value(&mut args, &arg)?),
+            "--manifest" => cli.manifest = Some(next_arg_value(&mut args, &arg)?),
+            "--defaults-on-error" => cli.defaults_on_error = true,
+            "--shortlist" => cli.shortlist = Some(next_arg_value(&mut args, &arg)?),
+            "--stream-json" => cli.stream_json = true,
+            "--output-dir" => cli.output_dir = Some(next_arg_value(&mut args, &arg)?),
+            "--strict" => cli.strict = true,
+            "--export-top" => cli.export_top = Some(next_arg_value(&mut args, &arg)?),
+            "--profile" => cli.profile = Some(next_arg_value(&mut args, &arg)?),
+            "--fuzz" => {
+                let template = next_arg_value(&mut args, &arg)?;
+                let value = next_arg_value(&mut args, &arg)?;
+                let count = value
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("Invalid value for --fuzz: {}", value))?;
+                cli.fuzz = Some((template, count));
+            }
+            other => return Err(format!("Unknown argument: {}", other).into()),
+        }
+    }
+
+    if cli.profile.is_some() && cli.manifest.is_some() {
+        return Err("--profile cannot be used with --manifest".into());
+    }
+    if cli.fuzz.is_some() && (cli.manifest.is_some() || cli.shortlist.is_some()) {
+        return Err("--fuzz cannot be used with --manifest or --shortlist".into());
+    }
+    let has_cleanup_filter = cli.older_than_days.is_some() || cli.keep_last.is_some();
+    if matches!(cli.command, CliCommand::CleanLogs) {
+        if !has_cleanup_filter {
+            return Err("clean-logs needs --older-than <days> or --keep-last <N>".into());
+        }
+    } else if has_cleanup_filter || cli.dry_run {
+        return Err("--older-than, --keep-last and --dry-run can only be used with clean-logs".into());
+    }
+
+    Ok(cli)
+}
+
+fn parse_duration_arg(value: &str) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((idx, 's')) => (&value[..idx], 1),
+        Some((idx, 'm')) => (&value[..idx], 60),
+        Some((idx, 'h')) => (&value[..idx], 3600),
+        _ => (value, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .map(|n| Duration::from_secs(n * multiplier))
+        .ok_or_else(|| format!("Invalid duration: {} (expected e.g. 90, 90s, 10m, 1h)", value).into())
+}
+
+fn next_arg_value(
+    args: &mut impl Iterator<Item = String>,
+    name: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}", name).into())
+}
+
+fn filter_domains(
+    domains: Vec<DomainSpec>,
+    pattern: &str,
+) -> Result<Vec<DomainSpec>, Box<dyn std::error::Error + Send + Sync>> {
+    let regex = regex::Regex::new(pattern)
+        .map_err(|e| format!("Invalid domain filter '{}': {}", pattern, e))?;
+
+    let total = domains.len();
+    let filtered: Vec<DomainSpec> = domains.into_iter().filter(|d| regex.is_match(&d.name)).collect();
+
+    print_status(
+        "[+]",
+        &format!("Фильтр доменов '{}': подходит {} из {}", pattern, filtered.len(), total),
+    );
+
+    if filtered.is_empty() {
+        return Err(format!("No domains match filter '{}'", pattern).into());
+    }
+
+    Ok(filtered)
+}
+
+const BUILTIN_SETTINGS: &str = r#"
+group_size = 10
+start_port = 10800
+group_delay_ms = 500
+request_timeout_sec = 5
+log_dir = "logs"
+results_file = "results.txt"
+ciadpi_start_delay_ms = 500
+"#;
+
+fn load_settings(
+    defaults_on_error: bool,
+    profile: Option<&str>,
+) -> Result<Settings, Box<dyn std::error::Error + Send + Sync>> {
+    print_status("[+]", "Загружаем настройки из settings.toml...");
+    let mut content = std::fs::read_to_string("settings.toml")
+        .map_err(|e| format!("Failed to read settings.toml: {}", e))?;
+    if let Some(name) = profile {
+        content = apply_profile(&content, name)?;
+    }
+    
+    match toml::from_str(&content) {
+        Ok(settings) => Ok(settings),
+        Err(e) if defaults_on_error => {
+            print_status("[WARN]", &format!("Ошибка в settings.toml: {}", e.message()));
+            load_settings_with_defaults(&content)
+        }
+        Err(e) => Err(format!("Failed to parse settings.toml: {}", e).into()),
+    }
+}
+
+/// Merges `[profiles.<name>]` over the base settings and drops the other profiles.
+fn apply_profile(content: &str, name: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut table: toml::Table = content
+        .parse()
+        .map_err(|e| format!("Failed to parse settings.toml: {}", e))?;
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        _ => toml::Table::new(),
+    };
+    let Some(toml::Value::Table(profile)) = profiles.get(name) else {
+        let available: Vec<&String> = profiles.keys().collect();
+        return Err(format!(
+            "Profile '{}' not found in settings.toml (available: {:?})",
+            name, available
+        )
+        .into());
+    };
+
+    print_status("[+]", &format!("Применяем профиль '{}': {} параметров", name, profile.len()));
+    table.extend(profile.clone());
+    Ok(toml::to_string(&table)?)
+}
+
+fn load_settings_with_defaults(content: &str) -> Result<Settings, Box<dyn std::error::Error + Send + Sync>> {
+    let mut merged: toml::Table = BUILTIN_SETTINGS.parse()?;
+
+    match content.parse::<toml::Table>() {
+        Ok(user) => {
+            let mut rejected = Vec::new();
+            for (key, value) in user {
+                let mut candidate = merged.clone();
+                candidate.insert(key.clone(), value);
+                if candidate.clone().try_into::<Settings>().is_ok() {
+                    merged = candidate;
+                } else {
+                    rejected.push(key);
+                }
+            }
+            if !rejected.is_empty() {
+                print_status(
+                    "[WARN]",
+                    &format!("Не удалось разобрать поля: {}, используются значения по умолчанию", rejected.join(", ")),
+                );
+            }
+        }
+        Err(_) => print_status("[WARN]", "Файл не удалось разобрать целиком, используются все значения по умолчанию"),
+    }
+
+    merged
+        .try_into()
+        .map_err(|e| format!("Failed to apply default settings: {}", e).into())
+}
+
+fn load_cli_input(cli: &CliArgs) -> Result<RunInput, Box<dyn std::error::Error + Send + Sync>> {
+    let mut input = match &cli.manifest {
+        Some(path) => load_manifest(path)?,
+        None => RunInput {
+            settings: load_settings(cli.defaults_on_error, cli.profile.as_deref())?,
+            configs: Vec::new(),
+            domains: read_domains("domains.txt")?,
+        },
+    };
+
+    if let Some((template, count)) = &cli.fuzz {
+        input.configs = generate_fuzz_configs(template, *count)?;
+        print_status(
+            "[+]",
+            &format!("Сгенерировано конфигов по шаблону '{}': {}", template, input.configs.len()),
+        );
+    } else if let Some(path) = &cli.shortlist {
+        input.configs = read_lines(path)?;
+        print_status("[+]", &format!("Проверяются только конфиги из {}: {}", path, input.configs.len()));
+    } else if cli.manifest.is_none() {
+        input.configs = read_lines("configs.txt")?;
+    }
+
+    if let Some(dir) = &cli.output_dir {
+        apply_output_dir(&mut input.settings, dir)?;
+    }
+    apply_start_port_override(&mut input.settings, cli)?;
+    resolve_success_rules(&mut input.settings)?;
+
+    Ok(input)
+}
+
+/// Fills every `{min..max}` placeholder with a random number and every
+/// `{a|b|c}` placeholder with a random choice, producing distinct configs.
+fn generate_fuzz_configs(template: &str, count: usize) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    const ATTEMPTS_PER_CONFIG: usize = 20;
+
+    let placeholder = regex::Regex::new(r"\{([^{}]*)\}")?;
+    if !placeholder.is_match(template) {
+        return Err(format!("Fuzz template '{}' has no {{min..max}} or {{a|b}} placeholders", template).into());
+    }
+    for caps in placeholder.captures_iter(template) {
+        parse_fuzz_placeholder(&caps[1])?;
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut configs: Vec<String> = Vec::with_capacity(count);
+    for _ in 0..count * ATTEMPTS_PER_CONFIG {
+        if configs.len() == count {
+            break;
+        }
+        let config = placeholder
+            .replace_all(template, |caps: &regex::Captures| {
+                match parse_fuzz_placeholder(&caps[1]) {
+                    Ok(FuzzPlaceholder::Range(min, max)) => rng.gen_range(min..=max).to_string(),
+                    Ok(FuzzPlaceholder::Choice(options)) => options[rng.gen_range(0..options.len())].to_string(),
+                    Err(_) => caps[0].to_string(),
+                }
+            })
+            .into_owned();
+        if !configs.contains(&config) {
+            configs.push(config);
+        }
+    }
+
+    Ok(configs)
+}
+
+enum FuzzPlaceholder<'a> {
+    Range(i64, i64),
+    Choice(Vec<&'a str>),
+}
+
+fn parse_fuzz_placeholder(content: &str) -> Result<FuzzPlaceholder<'_>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some((min, max)) = content.split_once("..") {
+        let min: i64 = min.trim().parse().map_err(|_| format!("Invalid fuzz range {{{}}}", content))?;
+        let max: i64 = max.trim().parse().map_err(|_| format!("Invalid fuzz range {{{}}}", content))?;
+        if min > max {
+            return Err(format!("Invalid fuzz range {{{}}}: min is above max", content).into());
+        }
+        return Ok(FuzzPlaceholder::Range(min, max));
+    }
+    if content.contains('|') {
+        return Ok(FuzzPlaceholder::Choice(content.split('|').collect()));
+    }
+    Err(format!("Unknown fuzz placeholder {{{}}}", content).into())
+}
+
+fn load_cli_settings(cli: &CliArgs) -> Result<Settings, Box<dyn std::error::Error + Send + Sync>> {
+    let mut settings = match &cli.manifest {
+        Some(path) => load_manifest(path)?.settings,
+        None => load_settings(cli.defaults_on_error, cli.profile.as_deref())?,
+    };
+    if let Some(dir) = &cli.output_dir {
+        apply_output_dir(&mut settings, dir)?;
+    }
+    apply_start_port_override(&mut settings, cli)?;
+    resolve_success_rules(&mut settings)?;
+    Ok(settings)
+}
+
+const START_PORT_ENV: &str = "BDPI_START_PORT";
+
+/// `--start-port` wins over the `BDPI_START_PORT` environment variable, which
+/// wins over `start_port` from the settings file.
+fn apply_start_port_override(settings: &mut Settings, cli: &CliArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (port, source) = match (cli.start_port, std::env::var(START_PORT_ENV)) {
+        (Some(port), _) => (port, "--start-port"),
+        (None, Ok(value)) => {
+            let port = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {}", START_PORT_ENV, value))?;
+            (port, START_PORT_ENV)
+        }
+        (None, Err(_)) => return Ok(()),
+    };
+
+    settings.start_port = port;
+    print_status("[+]", &format!("Стартовый порт {} задан через {}", port, source));
+    Ok(())
+}
+
+fn resolve_success_rules(settings: &mut Settings) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    settings.rules = match &settings.rules_file {
+        Some(path) => {
+            print_status("[+]", &format!("Загружаем критерии успеха из {}...", path));
+            if !settings.fail_status.is_empty() || settings.require_header.is_some() {
+                print_status("[WARN]", "fail_status и require_header игнорируются: используется rules_file");
+            }
+            SuccessRules::load(path)?
+        }
+        None => SuccessRules::from_settings(settings),
+    };
+    Ok(())
+}
+
+/// Moves every artifact of the run under `dir`: `dir` becomes `log_dir`, so session
+/// folders go there, and the results, SQLite, HTML, matrix and watch history
+/// files keep only their file name. Any directory in those paths is dropped,
+/// including an absolute one, so `/tmp/out/results.txt` becomes `<dir>/results.txt`.
+fn apply_output_dir(settings: &mut Settings, dir: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+
+    let relocate = |path: &str| {
+        let file_name = Path::new(path).file_name().unwrap_or_else(|| path.as_ref());
+        Path::new(dir).join(file_name).display().to_string()
+    };
+    settings.results_file = relocate(&settings.results_file);
+    if let Some(db_path) = &settings.results_sqlite {
+        settings.results_sqlite = Some(relocate(db_path));
+    }
+    if let Some(html_path) = &settings.results_html_file {
+        settings.results_html_file = Some(relocate(html_path));
+    }
+    if let Some(matrix_path) = &settings.matrix_file {
+        settings.matrix_file = Some(relocate(matrix_path));
+    }
+    settings.watch_history_file = relocate(&settings.watch_history_file);
+    settings.log_dir = dir.to_string();
+    Ok(())
+}
+
+fn load_manifest(path: &str) -> Result<RunInput, Box<dyn std::error::Error + Send + Sync>> {
+    print_status("[+]", &format!("Загружаем настройки, конфиги и домены из {}...", path));
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let manifest: Manifest = serde_yaml::from_str(&content)
+        .map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+
+    let configs = manifest
+        .configs
+        .iter()
+        .map(|config| config.trim().to_string())
+        .filter(|config| !config.is_empty())
+        .collect();
+    let domains = manifest
+        .domains
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| DomainSpec::parse(line).map_err(|e| format!("Failed to parse {}: {}", path, e).into()))
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error + Send + Sync>>>()?;
+
+    Ok(RunInput {
+        settings: manifest.settings,
+        configs,
+        domains,
+    })
+}
+
+fn read_lines(filename: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let file = File::open(filename)
+        .map_err(|e| format!("Failed to open {}: {}", filename, e))?;
+    
+    BufReader::new(file)
+        .lines()
+        .map(|line| line.map(|l| l.trim().to_string()))
+        .filter(|line| line.as_ref().map(|l| !l.is_empty()).unwrap_or(false))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to read lines from {}: {}", filename, e).into())
+}
+
+fn reorder_configs_file(
+    filename: &str,
+    ranks: &[ConfigRank],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let content = std::fs::read_to_string(filename)
+        .map_err(|e| format!("Failed to read {}: {}", filename, e))?;
+
+    let rates: HashMap<&str, f32> = ranks
+        .iter()
+        .map(|r| (r.config.as_str(), r.success_rate))
+        .collect();
+
+    let mut lines: Vec<(&str, Option<f32>)> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| (line, rates.get(line.trim()).copied()))
+        .collect();
+
+    lines.sort_by(|(_, a), (_, b)| match (a, b) {
+        (Some(a), Some(b)) => b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+
+    let backup = format!("{}.bak", filename);
+    std::fs::copy(filename, &backup)
+        .map_err(|e| format!("Failed to back up {}: {}", filename, e))?;
+
+    let reordered: String = lines.iter().map(|(line, _)| format!("{}\n", line)).collect();
+    std::fs::write(filename, reordered)
+        .map_err(|e| format!("Failed to write {}: {}", filename, e))?;
+
+    print_status(
+        "[+]",
+        &format!("{} пересортирован по успешности (копия: {})", filename, backup),
+    );
+    Ok(())
+}
+
+/// Accepts either a 1-based position in the config list or the config itself.
+fn resolve_baseline_config(value: &str, configs: &[String]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(index) = value.parse::<usize>() {
+        return index
+            .checked_sub(1)
+            .and_then(|i| configs.get(i))
+            .cloned()
+            .ok_or_else(|| format!("Baseline config index {} is out of range 1..={}", index, configs.len()).into());
+    }
+
+    let value = value.trim();
+    configs
+        .iter()
+        .find(|config| config.as_str() == value)
+        .cloned()
+        .ok_or_else(|| format!("Baseline config '{}' is not in the tested configs", value).into())
+}
+
+fn export_top_configs(
+    filename: &str,
+    ranks: &[ConfigRank],
+    min_success_rate: Option<f32>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const TOP_COUNT: usize = 10;
+
+    let mut sorted: Vec<&ConfigRank> = ranks
+        .iter()
+        .filter(|r| r.successful > 0 && min_success_rate.is_none_or(|min| r.success_rate >= min))
+        .collect();
+    sorted.sort_by(|a, b| {
+        b.success_rate
+            .partial_cmp(&a.success_rate)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| b.successful.cmp(&a.successful))
+    });
+
+    let mut exported: Vec<&str> = Vec::new();
+    for rank in sorted {
+        if exported.len() == TOP_COUNT {
+            break;
+        }
+        if !exported.contains(&rank.config.as_str()) {
+            exported.push(&rank.config);
+        }
+    }
+
+    let content: String = exported.iter().map(|config| format!("{}\n", config)).collect();
+    std::fs::write(filename, content)
+        .map_err(|e| format!("Failed to write {}: {}", filename, e))?;
+
+    print_status("[+]", &format!("Лучшие конфиги ({}) сохранены в {}", exported.len(), filename));
+    Ok(())
+}
+
+fn read_domains(filename: &str) -> Result<Vec<DomainSpec>, Box<dyn std::error::Error + Send + Sync>> {
+    read_lines(filename)?
+        .iter()
+        .map(|line| DomainSpec::parse(line).map_err(|e| format!("Failed to parse {}: {}", filename, e).into()))
+        .collect()
+}
+
+fn create_session_directory(log_dir: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
+    let session_dir = PathBuf::from(log_dir).join(timestamp.to_string());
+    create_dir_all(&session_dir)?;
+    Ok(session_dir)
+}
+
+fn session_results_path(session_dir: &Path, results_file: &str) -> String {
+    let shared = Path::new(results_file);
+    let stem = shared
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| "results".to_string());
+    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
+
+    let file_name = match shared.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, timestamp, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, timestamp),
+    };
+
+    session_dir.join(file_name).display().to_string()
+}
+
+fn write_session_snapshot(
+    session_dir: &Path,
+    settings: &Settings,
+    configs: &[String],
+    domains: &[DomainSpec],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let domain_lines: Vec<&str> = domains.iter().map(|d| d.line.as_str()).collect();
+    std::fs::write(session_dir.join("settings.snapshot.toml"), toml::to_string(settings)?)?;
+    std::fs::write(session_dir.join("configs.snapshot.txt"), configs.join("\n") + "\n")?;
+    std::fs::write(session_dir.join("domains.snapshot.txt"), domain_lines.join("\n") + "\n")?;
+    Ok(())
+}
+
+fn validate_port_pool(
+    settings: &Settings,
+    config_count: usize,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if settings.port_pool.is_empty() {
+        let generations = port_generation(settings, config_count.div_ceil(settings.group_size).saturating_sub(1));
+        let last_port = settings.start_port as usize + (generations + 1) * settings.group_size - 1;
+        if last_port > u16::MAX as usize {
+            return Err(format!(
+                "start_port {} needs ports up to {}, which is above 65535",
+                settings.start_port, last_port
+            )
+            .into());
+        }
+        return Ok(());
+    }
+
+    let simultaneous = settings.group_size.min(config_count);
+    if settings.port_pool.len() < simultaneous {
+        return Err(format!(
+            "port_pool has {} ports but up to {} configs run simultaneously",
+            settings.port_pool.len(),
+            simultaneous
+        )
+        .into());
+    }
+    // Rotation moves a group to the next group_size ports of the pool; with
+    // fewer than two groups' worth they wrap onto the ports just released.
+    let rotates = port_generation(settings, config_count.div_ceil(settings.group_size).saturating_sub(1)) > 0;
+    if rotates && settings.port_pool.len() < 2 * simultaneous {
+        return Err(format!(
+            "port_pool has {} ports, max_reuses_per_port needs at least {} (twice the group size) to switch groups to fresh ports",
+            settings.port_pool.len(),
+            2 * simultaneous
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn group_ports(settings: &Settings, group_idx: usize, config_count: usize) -> Vec<u16> {
+    let generation = port_generation(settings, group_idx);
+    if settings.port_pool.is_empty() {
+        let first = settings.start_port as usize + generation * settings.group_size;
+        (0..config_count)
+            .map(|i| (first + i) as u16)
+            .collect()
+    } else {
+        let offset = generation * settings.group_size;
+        (0..config_count)
+            .map(|i| settings.port_pool[(offset + i) % settings.port_pool.len()])
+            .collect()
+    }
+}
+
+/// How many times the group's ports have been rotated: with `max_reuses_per_port`
+/// each port serves at most that many groups after its first one.
+fn port_generation(settings: &Settings, group_idx: usize) -> usize {
+    settings
+        .max_reuses_per_port
+        .map_or(0, |reuses| group_idx / (reuses + 1))
+}
+
+async fn run_all_groups(
+    configs: &[String],
+    domains: &[DomainSpec],
+    settings: &Settings,
+    session_dir: Option<&Path>,
+    results: Arc<Mutex<ResultStore>>,
+) -> Result<Vec<GroupStats>, Box<dyn std::error::Error + Send + Sync>> {
+    let available_groups = configs.len().div_ceil(settings.group_size);
+    let total_groups = settings
+        .max_groups
+        .map_or(available_groups, |max| max.min(available_groups));
+    let mut group_stats = Vec::with_capacity(total_groups);
+    let run_started = std::time::Instant::now();
+    let port_tracker = Arc::new(PortTracker::default());
+
+    let mut chunks: Vec<&[String]> = configs.chunks(settings.group_size).collect();
+    match settings.group_order {
+        GroupOrder::Sequential => {}
+        GroupOrder::Reverse => chunks.reverse(),
+        GroupOrder::Random => chunks.shuffle(&mut rand::thread_rng()),
+    }
+
+    for (group_idx, chunk) in chunks.into_iter().take(total_groups).enumerate() {
+        let group_number = group_idx + 1;
+        
+        let ports = group_ports(settings, group_idx, chunk.len());
+        let eta = estimate_remaining(run_started.elapsed(), group_idx, total_groups);
+        print_group_header(group_number, total_groups, &ports, settings.group_size, eta);
+        
+        let group_dir = session_dir.map(|dir| dir.join(format!("group_{}", group_number)));
+        if let Some(dir) = &group_dir {
+            create_dir_all(dir)?;
+        }
+
+        let started_sec = run_elapsed_sec();
+        let mut stats = process_group(
+            chunk,
+            &ports,
+            domains,
+            settings,
+            group_dir.as_deref(),
+            results.clone(),
+            port_tracker.clone(),
+        ).await?;
+        stats.started_sec = started_sec;
+        stats.finished_sec = run_elapsed_sec();
+
+        print_group_summary(group_number, &stats);
+        warn_fast_rebinds(&port_tracker.take_fast_rebinds(), settings);
+        if let (Some(dir), Some(max_files)) = (session_dir, settings.max_log_files) {
+            let removed = prune_session_logs(dir, max_files);
+            if removed > 0 {
+                print_status("[~]", &format!("Удалено старых логов ciadpi: {} (max_log_files = {})", removed, max_files));
+            }
+        }
+        let aborted = stats.aborted;
+        group_stats.push(stats);
+
+        save_intermediate_results(&results, settings).await?;
+
+        if aborted {
+            print_status("[ERROR]", "Проверка остановлена, результаты завершённых конфигов сохранены");
+            break;
+        }
+
+        if group_number < total_groups {
+            wait_between_groups(settings.group_delay_ms).await;
+        }
+    }
+
+    let (fast_rebinds, busiest) = port_tracker.summary();
+    if let (true, Some((port, binds))) = (fast_rebinds > 0, busiest) {
+        console!();
+        print_status(
+            "[WARN]",
+            &format!(
+                "Быстрых повторных занятий портов за сессию: {} (больше всего занятий у порта {}: {}). \
+                 Если конфиги в поздних группах падают без видимой причины, увеличьте group_delay_ms или задайте max_reuses_per_port",
+                fast_rebinds, port, binds
+            ),
+        );
+    }
+
+    if total_groups < available_groups {
+        console!();
+        print_status(
+            "[~]",
+            &format!(
+                "Запуск ограничен max_groups: выполнено {} из {} групп",
+                total_groups, available_groups
+            ),
+        );
+    }
+
+    Ok(group_stats)
+}
+
+fn warn_fast_rebinds(rebinds: &[(u16, Duration)], settings: &Settings) {
+    let Some(shortest) = rebinds.iter().map(|&(_, gap)| gap).min() else {
+        return;
+    };
+    let mut ports: Vec<u16> = rebinds.iter().map(|&(port, _)| port).collect();
+    ports.sort_unstable();
+    ports.dedup();
+    print_status(
+        "[WARN]",
+        &format!(
+            "Повторное занятие портов {} через {} мс после освобождения (port_rebind_min_interval_ms = {})",
+            format_ports(&ports),
+            shortest.as_millis(),
+            settings.port_rebind_min_interval_ms
+        ),
+    );
+}
+
+async fn process_group(
+    configs: &[String],
+    ports: &[u16],
+    domains: &[DomainSpec],
+    settings: &Settings,
+    group_dir: Option<&Path>,
+    results: Arc<Mutex<ResultStore>>,
+    port_tracker: Arc<PortTracker>,
+) -> Result<GroupStats, Box<dyn std::error::Error + Send + Sync>> {
+    let mut tasks = Vec::with_capacity(configs.len());
+    let partial_group = (settings.scale_partial_group && configs.len() < settings.group_size).then_some(configs.len());
+    let (limiter, ramp) = create_request_limiter(settings, configs.len() * domains.len(), partial_group);
+    if let (Some(_), Some(_)) = (partial_group, settings.max_concurrent_requests) {
+        console!(
+            "   Лимит одновременных запросов: {} (уменьшен под размер группы)",
+            limiter.available_permits()
+        );
+    }
+    let group = Arc::new(GroupContext {
+        limiter,
+        counters: LiveCounters::default(),
+        config_count: configs.len(),
+        spawn_failures: AtomicUsize::new(0),
+        abort_threshold: settings.group_abort_on_spawn_failures,
+        reserve_ports: std::sync::Mutex::new(settings.reserve_ports.iter().rev().copied().collect()),
+        port_tracker,
+        progress: start_group_progress(),
+    });
+
+    let live_status = spawn_live_status(group.clone());
+    for (config, &socks5_port) in configs.iter().zip(ports) {
+        let task = spawn_config_test(
+            config.clone(),
+            socks5_port,
+            domains.to_vec(),
+            group_dir.map(Path::to_path_buf),
+            settings.clone(),
+            group.clone(),
+            results.clone(),
+        );
+        
+        print_config_start(config, socks5_port);
+        tasks.push(task);
+    }
+
+    let mut stats = GroupStats::default();
+
+    for (i, task) in tasks.into_iter().enumerate() {
+        match task.await {
+            Ok(Some(summary)) => {
+                stats.bytes_received += summary.bytes_received;
+                print_config_result(&summary.name, summary.successful, summary.total);
+                stats.process_exits.push((summary.name, summary.process_exit));
+            }
+            Ok(None) => print_config_error(&configs[i]),
+            Err(e) => print_status("[ERROR]", &format!("Task execution failed: {:?}", e)),
+        }
+    }
+
+    if let Some(ramp) = ramp {
+        ramp.abort();
+    }
+    live_status.abort();
+    finish_group_progress(&group.progress);
+    (stats.successful, stats.total) = group.counters.snapshot();
+
+    if group.should_abort() {
+        stats.aborted = true;
+        console!();
+        print_status(
+            "[ERROR]",
+            &format!(
+                "Группа прервана: {} из {} процессов ciadpi не запустились",
+                group.spawn_failures.load(Ordering::SeqCst),
+                group.config_count
+            ),
+        );
+        print_status(
+            "[ERROR]",
+            "Вероятно, закончились свободные порты или ресурсы системы (лимит процессов/файлов)",
+        );
+    }
+
+    Ok(stats)
+}
+
+/// Keeps a line above the config progress bars with the group's running
+/// totals; hidden along with the bars when progress output is off.
+fn spawn_live_status(group: Arc<GroupContext>) -> tokio::task::JoinHandle<()> {
+    let style = ProgressStyle::with_template("   {msg}").unwrap_or_else(|_| ProgressStyle::default_bar());
+    let line = group.progress.add(ProgressBar::new_spinner().with_style(style));
+    tokio::spawn(async move {
+        let mut ticker = time::interval(Duration::from_millis(500));
+        loop {
+            ticker.tick().await;
+            let (successful, total) = group.counters.snapshot();
+            line.set_message(format!("Группа: {} успешно из {} проверено", successful, total));
+        }
+    })
+}
+
+/// Builds the group's request limiter. For a partial group (`partial_group` holds
+/// its config count) the configured limits shrink in proportion to `group_size`,
+/// so each config gets the same share of requests as in a full group.
+fn create_request_limiter(
+    settings: &Settings,
+    group_requests: usize,
+    partial_group: Option<usize>,
+) -> (Arc<Semaphore>, Option<tokio::task::JoinHandle<()>>) {
+    let scale = |limit: usize| {
+        partial_group.map_or(limit, |count| (limit * count).div_ceil(settings.group_size).max(1))
+    };
+    let target = settings
+        .max_concurrent_requests
+        .map(scale)
+        .unwrap_or(group_requests)
+        .clamp(1, Semaphore::MAX_PERMITS);
+
+    let initial = match settings.slow_start_initial.map(scale) {
+        Some(initial) if initial < target && settings.slow_start_ms > 0 => initial.max(1),
+        _ => return (Arc::new(Semaphore::new(target)), None),
+    };
+
+    let limiter = Arc::new(Semaphore::new(initial));
+    let ramp = spawn_slow_start(limiter.clone(), initial, target, settings.slow_start_ms);
+    (limiter, Some(ramp))
+}
+
+fn spawn_slow_start(
+    limiter: Arc<Semaphore>,
+    initial: usize,
+    target: usize,
+    ramp_ms: u64,
+) -> tokio::task::JoinHandle<()> {
+    const STEPS: usize = 10;
+
+    tokio::spawn(async move {
+        let step_permits = (target - initial).div_ceil(STEPS);
+        let step_delay = Duration::from_millis(ramp_ms / STEPS as u64);
+        let mut current = initial;
+
+        while current < target {
+            time::sleep(step_delay).await;
+            let added = step_permits.min(target - current);
+            limiter.add_permits(added);
+            current += added;
+        }
+    })
+}
+
+fn spawn_config_test(
+    config: String,
+    socks5_port: u16,
+    domains: Vec<DomainSpec>,
+    group_dir: Option<PathBuf>,
+    settings: Settings,
+    group: Arc<GroupContext>,
+    results: Arc<Mutex<ResultStore>>,
+) -> tokio::task::JoinHandle<Option<ConfigSummary>> {
+    tokio::spawn(async move {
+        run_config_test(&config, socks5_port, &domains, group_dir.as_deref(), &settings, &group, results)
+            .await
+            .ok()
+    })
+}
+
+async fn run_config_test(
+    config: &str,
+    socks5_port: u16,
+    domains: &[DomainSpec],
+    group_dir: Option<&Path>,
+    settings: &Settings,
+    group: &GroupContext,
+    results: Arc<Mutex<ResultStore>>,
+) -> Result<ConfigSummary, Box<dyn std::error::Error + Send + Sync>> {
+    let started_sec = run_elapsed_sec();
+    let (mut process, log_path, socks5_port) =
+        match start_ciadpi_bound(config, socks5_port, group_dir, settings, group).await {
+            Ok((process, log_path, port)) => (ProcessGuard(process), log_path, port),
+            Err(e) => {
+                group.record_spawn_failure();
+                return Err(e);
+            }
+        };
+
+    if group.should_abort() {
+        stop_process(&mut process.0);
+        group.port_tracker.release(socks5_port);
+        return Err("Group aborted after spawn failures".into());
+    }
+
+    let bar = config_progress_bar(&group.progress, config, domains.len());
+    let domain_results =
+        test_all_domains(domains, socks5_port, settings, &group.limiter, &group.counters, &bar).await;
+    bar.finish_and_clear();
+    let persistent_successes = match &settings.persistent_check {
+        Some(check) => Some(test_persistent_connection(socks5_port, check, settings).await),
+        None => None,
+    };
+
+    if settings.pre_kill_grace_ms > 0 {
+        time::sleep(Duration::from_millis(settings.pre_kill_grace_ms)).await;
+    }
+    let process_exit = stop_process(&mut process.0);
+    group.port_tracker.release(socks5_port);
+
+    let ciadpi_error = match &log_path {
+        Some(path) if domain_results.successful.is_empty() => extract_ciadpi_error(path),
+        _ => None,
+    };
+    if let Some(path) = log_path {
+        discard_unneeded_log(&path, settings.keep_logs, domain_results.failed.is_empty());
+    }
+
+    if is_proxy_auth_failure(&domain_results) {
+        group.counters.retract(0, domain_results.failed.len());
+        print_status(
+            "[FAIL]",
+            &format!(
+                "{}: proxy auth failed - ошибка авторизации SOCKS5, домены не помечаются как заблокированные",
+                extract_config_name(config)
+            ),
+        );
+        return Err("Proxy auth failed".into());
+    }
+
+    let summary = ConfigSummary {
+        name: extract_config_name(config),
+        successful: domain_results.successful.len(),
+        total: domain_results.successful.len() + domain_results.failed.len(),
+        bytes_received: domain_results.bytes_received,
+        process_exit,
+    };
+    let mut result = TestResult::new(config.to_string(), socks5_port, domain_results);
+    result.persistent_successes = persistent_successes;
+    result.started_sec = Some(started_sec);
+    result.finished_sec = Some(run_elapsed_sec());
+    result.finished_at = Some(Local::now().to_rfc3339());
+    if let Some(reason) = &ciadpi_error {
+        print_status("[FAIL]", &format!("{}: ciadpi сообщил об ошибке: {}", extract_config_name(config), reason));
+    }
+    result.ciadpi_error = ciadpi_error;
+    if result.successful_domains.is_empty() && !result.failed_domains.is_empty() {
+        result.network_down = is_network_down(settings).await;
+        if result.network_down {
+            print_status(
+                "[WARN]",
+                &format!(
+                    "{}: контрольный домен недоступен напрямую - сеть пропала во время проверки, а не конфиг плохой",
+                    extract_config_name(config)
+                ),
+            );
+        }
+    }
+    if settings.stream_json {
+        emit_json_line(&result);
+    }
+    
+    results.lock().await.push(result)?;
+
+    Ok(summary)
+}
+
+async fn is_network_down(settings: &Settings) -> bool {
+    let Some(control) = &settings.control_domain else {
+        return false;
+    };
+    match DomainSpec::parse(control) {
+        Ok(domain) => check_domain(&domain, None, settings).await.is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Opens one connection to the target through the proxy and sends sequential
+/// HTTP/1.1 requests over it, counting how many succeed before the first
+/// failure. The connection is never reopened, so a dropped connection ends the
+/// count instead of being hidden behind a reconnect.
+async fn test_persistent_connection(port: u16, check: &PersistentCheck, settings: &Settings) -> u32 {
+    let Ok(domain) = DomainSpec::parse(&check.target) else {
+        return 0;
+    };
+    let scheme = domain.scheme.unwrap_or(Scheme::Https);
+    let timeout = Duration::from_secs(settings.request_timeout_sec);
+    let proxy = settings.proxy_address_family.proxy_address(port);
+    let connect = persistent_connect(proxy, &domain, scheme, settings);
+    let Ok(Ok((mut sender, connection))) = time::timeout(timeout, connect).await else {
+        return 0;
+    };
+
+    let mut successes = 0;
+    for request in 0..check.requests {
+        if request > 0 {
+            time::sleep(Duration::from_millis(check.interval_ms)).await;
+        }
+        let exchange = persistent_request(&mut sender, &domain, scheme, settings);
+        if !time::timeout(timeout, exchange).await.unwrap_or(false) {
+            break;
+        }
+        successes += 1;
+    }
+    connection.abort();
+    successes
+}
+
+type PersistentConnection = (
+    hyper::client::conn::SendRequest<hyper::Body>,
+    tokio::task::JoinHandle<()>,
+);
+
+/// SOCKS5 connection to the target, wrapped in TLS for `https`, with the hyper
+/// connection driver running in the returned task.
+async fn persistent_connect(
+    proxy: std::net::SocketAddr,
+    domain: &DomainSpec,
+    scheme: Scheme,
+    settings: &Settings,
+) -> Result<PersistentConnection, FailureKind> {
+    let host = match scheme {
+        Scheme::Https => domain.connect_host(),
+        Scheme::Http => &domain.host,
+    }
+    .trim_start_matches('[')
+    .trim_end_matches(']');
+    let target_port = domain.port.unwrap_or(scheme.default_port());
+    let stream = if settings.local_dns {
+        let ip = resolve_ip(host, target_port).await.ok_or(FailureKind::Connect)?;
+        socks5_connect(proxy, &ip.to_string(), target_port).await?
+    } else {
+        socks5_connect(proxy, host, target_port).await?
+    };
+    let _ = stream.set_nodelay(settings.tcp_nodelay);
+
+    match scheme {
+        Scheme::Http => persistent_handshake(stream).await,
+        Scheme::Https => {
+            let connector = tokio_native_tls::native_tls::TlsConnector::new().map_err(|_| FailureKind::Other)?;
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(host, stream)
+                .await
+                .map_err(|_| FailureKind::Connect)?;
+            persistent_handshake(stream).await
+        }
+    }
+}
+
+async fn persistent_handshake<S>(stream: S) -> Result<PersistentConnection, FailureKind>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::Builder::new()
+        .handshake(stream)
+        .await
+        .map_err(|_| FailureKind::Connect)?;
+    let connection = tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok((sender, connection))
+}
+
+/// One request over the open connection; fails once the connection is closed.
+/// A redirect counts as a success, since following it would need another
+/// connection.
+async fn persistent_request(
+    sender: &mut hyper::client::conn::SendRequest<hyper::Body>,
+    domain: &DomainSpec,
+    scheme: Scheme,
+    settings: &Settings,
+) -> bool {
+    use hyper::header;
+
+    if futures::future::poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+        return false;
+    }
+    let Ok(url) = reqwest::Url::parse(&domain.url(scheme)) else {
+        return false;
+    };
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = hyper::Request::builder()
+        .method(domain.method.clone())
+        .uri(target)
+        .header(header::HOST, domain.authority());
+    let request = match &domain.body {
+        Some(body) => request
+            .header(header::CONTENT_TYPE, &domain.content_type)
+            .body(hyper::Body::from(body.clone())),
+        None => request.body(hyper::Body::empty()),
+    };
+    let Ok(request) = request else {
+        return false;
+    };
+
+    let Ok(response) = sender.send_request(request).await else {
+        return false;
+    };
+    let (head, body) = response.into_parts();
+    let accepted = head.status.is_redirection()
+        || (is_expected_status(head.status, domain, settings) && settings.rules.accepts_headers(&head.headers));
+    accepted && hyper::body::to_bytes(body).await.is_ok()
+}
+
+fn is_proxy_auth_failure(results: &DomainResults) -> bool {
+    results.successful.is_empty()
+        && !results.failed.is_empty()
+        && results
+            .failure_reasons
+            .values()
+            .all(|kind| *kind == FailureKind::ProxyAuth)
+}
+
+fn emit_json_line(result: &TestResult) {
+    write_output(Output::Stdout, &stream_json_line(result).to_string());
+}
+
+fn stream_json_line(result: &TestResult) -> serde_json::Value {
+    serde_json::json!({
+        "config_id": config_id(&result.config),
+        "config": result.config,
+        "port": result.socks5_port,
+        "successful": result.successful_domains.len(),
+        "total": result.successful_domains.len() + result.failed_domains.len(),
+        "success_rate": result.success_rate,
+        "started_sec": result.started_sec,
+        "finished_sec": result.finished_sec,
+        "finished_at": result.finished_at,
+    })
+}
+
+fn mark_run_started() {
+    if let Ok(mut started) = RUN_STARTED.lock() {
+        *started = Some(std::time::Instant::now());
+    }
+}
+
+/// Seconds since the run started, rounded to milliseconds.
+fn run_elapsed_sec() -> f64 {
+    let elapsed = RUN_STARTED
+        .lock()
+        .map(|mut started| started.get_or_insert_with(std::time::Instant::now).elapsed())
+        .unwrap_or_default();
+    (elapsed.as_secs_f64() * 1000.0).round() / 1000.0
+}
+
+fn ciadpi_start_delay(settings: &Settings) -> Duration {
+    let jitter = if settings.ciadpi_start_delay_jitter_ms > 0 {
+        rand::thread_rng().gen_range(0..=settings.ciadpi_start_delay_jitter_ms)
+    } else {
+        0
+    };
+    Duration::from_millis(settings.ciadpi_start_delay_ms + jitter)
+}
+
+async fn run_spot_check(
+    config: &str,
+    domain_line: &str,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let domain = DomainSpec::parse(domain_line)?;
+    let port = group_ports(settings, 0, 1)[0];
+
+    print_section(&format!("SPOT CHECK: {} → {}", display_config(config, settings), domain.name));
+    if let Some(target_port) = domain.port {
+        print_status("[+]", &format!("Порт назначения: {}", target_port));
+    }
+    print_config_start(config, port);
+
+    let (process, _) = start_ciadpi_process(config, port, None, settings).await?;
+    let mut process = ProcessGuard(process);
+    time::sleep(ciadpi_start_delay(settings)).await;
+
+    let client = create_http_client(Some(port), settings)?;
+    let schemes: &[Scheme] = match domain.scheme {
+        Some(Scheme::Https) => &[Scheme::Https],
+        Some(Scheme::Http) => &[Scheme::Http],
+        None => &[Scheme::Https, Scheme::Http],
+    };
+
+    for &scheme in schemes {
+        if spot_request(&client, &domain, scheme, settings).await {
+            break;
+        }
+    }
+
+    stop_process(&mut process.0);
+    Ok(())
+}
+
+async fn run_stability_check(
+    config: &str,
+    domain_line: &str,
+    duration: Duration,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let domain = DomainSpec::parse(domain_line)?;
+    let port = group_ports(settings, 0, 1)[0];
+    let interval = Duration::from_millis(settings.stability_interval_ms.max(1));
+
+    print_section(&format!("STABILITY: {} → {}", display_config(config, settings), domain.name));
+    console!(
+        "   Длительность: {}, интервал запросов: {} мс",
+        format_duration(duration),
+        interval.as_millis()
+    );
+    print_config_start(config, port);
+
+    let (process, _) = start_ciadpi_process(config, port, None, settings).await?;
+    let mut process = ProcessGuard(process);
+    time::sleep(ciadpi_start_delay(settings)).await;
+
+    let started = std::time::Instant::now();
+    let mut timeline = String::new();
+    let mut successes = 0;
+    let mut current_streak = 0;
+    let mut longest_streak = 0;
+
+    while started.elapsed() < duration {
+        let attempt_started = std::time::Instant::now();
+        let result = check_domain(&domain, Some(port), settings).await;
+        let time = Local::now().format("%H:%M:%S");
+
+        match result {
+            Ok(_) => {
+                successes += 1;
+                current_streak = 0;
+                timeline.push('+');
+                print_status(
+                    "[OK]",
+                    &format!("{} ответ за {} мс", time, attempt_started.elapsed().as_millis()),
+                );
+            }
+            Err(kind) => {
+                current_streak += 1;
+                longest_streak = longest_streak.max(current_streak);
+                timeline.push('-');
+                print_status("[FAIL]", &format!("{} {}", time, kind.label()));
+            }
+        }
+
+        let remaining = duration.saturating_sub(started.elapsed());
+        time::sleep(interval.saturating_sub(attempt_started.elapsed()).min(remaining)).await;
+    }
+
+    let process_exit = stop_process(&mut process.0);
+    let attempts = timeline.len();
+    let rate = if attempts > 0 { successes as f32 / attempts as f32 * 100.0 } else { 0.0 };
+
+    console!();
+    print_table(&[
+        ("Запросов:", &attempts.to_string()),
+        ("Успешных:", &format!("{} ({:.1}%)", successes, rate)),
+        ("Самая длинная серия неудач:", &longest_streak.to_string()),
+        ("Процесс ciadpi:", &process_exit.describe()),
+    ]);
+    console!("   Хронология (+ успех, - неудача):");
+    for chunk in timeline.as_bytes().chunks(60) {
+        console!("   {}", String::from_utf8_lossy(chunk));
+    }
+    console!();
+
+    Ok(())
+}
+
+async fn run_external_proxy(
+    port: u16,
+    domains: &[DomainSpec],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let address = settings.proxy_address_family.proxy_address(port);
+    let name = format!("external proxy {}", address);
+
+    print_section(&format!("ВНЕШНИЙ ПРОКСИ: {}", address));
+    console!("   Доменов для проверки: {}", domains.len());
+    console!();
+
+    let (limiter, ramp) = create_request_limiter(settings, domains.len(), None);
+    let domain_results =
+        test_all_domains(domains, port, settings, &limiter, &LiveCounters::default(), &ProgressBar::hidden()).await;
+    if let Some(ramp) = ramp {
+        ramp.abort();
+    }
+
+    let stats = GroupStats {
+        successful: domain_results.successful.len(),
+        total: domain_results.successful.len() + domain_results.failed.len(),
+        bytes_received: domain_results.bytes_received,
+        ..GroupStats::default()
+    };
+    print_config_result(&name, stats.successful, stats.total);
+
+    let mut results = ResultStore::new(settings)?;
+    results.push(TestResult::new(name, port, domain_results))?;
+    let summary = ReportSummary::collect(&results)?;
+    write_results_file(&results, &summary, settings)?;
+    show_final_results(&stats, &summary, None, &settings.results_file);
+    show_recommended(&summary, settings);
+
+    Ok(())
+}
+
+async fn run_bisect(
+    config: &str,
+    domains: &[DomainSpec],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const BISECT_DOMAINS: usize = 10;
+
+    let domains = &domains[..domains.len().min(BISECT_DOMAINS)];
+    let port = group_ports(settings, 0, 1)[0];
+    let args = split_config_args(config);
+
+    print_section(&format!("BISECT: {}", display_config(config, settings)));
+    console!("   Аргументов: {}, доменов для проверки: {}", args.len(), domains.len());
+    console!();
+
+    let full_rate = match bisect_probe(&args, port, domains, settings).await {
+        BisectProbe::Rate(rate) => rate,
+        BisectProbe::Inconclusive(reason) => {
+            return Err(format!("Full config could not be tested: {}", reason).into());
+        }
+    };
+    if full_rate >= BISECT_WORKING_RATE {
+        print_status("[+]", &format!("Полный конфиг: {:.1}% - ищем минимальный рабочий набор флагов", full_rate));
+        bisect_working(args, full_rate, port, domains, settings).await;
+    } else {
+        print_status(
+            "[+]",
+            &format!("Полный конфиг: {:.1}% - ищем флаги, без которых конфиг начинает работать", full_rate),
+        );
+        bisect_failing(&args, port, domains, settings).await;
+    }
+    console!();
+
+    Ok(())
+}
+
+/// Success rate from which a bisect variant counts as working.
+const BISECT_WORKING_RATE: f32 = 50.0;
+
+/// Greedily drops one argument at a time, keeping the drop whenever the
+/// reduced config works no worse than the full one.
+async fn bisect_working(
+    mut args: Vec<String>,
+    full_rate: f32,
+    port: u16,
+    domains: &[DomainSpec],
+    settings: &Settings,
+) {
+    let mut idx = 0;
+    while idx < args.len() {
+        let mut candidate = args.clone();
+        let removed = candidate.remove(idx);
+        match bisect_probe(&candidate, port, domains, settings).await {
+            BisectProbe::Rate(rate) if rate >= full_rate => {
+                print_status("[~]", &format!("Без {}: {:.1}% - флаг не влияет", removed, rate));
+                args = candidate;
+            }
+            BisectProbe::Rate(rate) => {
+                print_status("[+]", &format!("Без {}: {:.1}% - флаг нужен", removed, rate));
+                idx += 1;
+            }
+            BisectProbe::Inconclusive(reason) => {
+                print_status("[WARN]", &format!("Без {}: не удалось проверить ({}) - флаг оставлен", removed, reason));
+                idx += 1;
+            }
+        }
+    }
+
+    print_section("РЕЗУЛЬТАТ BISECT");
+    if args.is_empty() {
+        console!("   Конфиг работает и без аргументов - флаги для этих доменов не нужны");
+    } else {
+        console!("   Минимальный рабочий набор: {}", args.join(" "));
+    }
+}
+
+/// Looks for the flags whose removal makes a failing config work: each single
+/// flag first, then pairs when no single flag is enough.
+async fn bisect_failing(args: &[String], port: u16, domains: &[DomainSpec], settings: &Settings) {
+    let mut culprits: Vec<(String, f32)> = Vec::new();
+    let mut inconclusive = 0;
+
+    let singles: Vec<Vec<usize>> = (0..args.len()).map(|i| vec![i]).collect();
+    let pairs: Vec<Vec<usize>> = (0..args.len())
+        .flat_map(|i| (i + 1..args.len()).map(move |j| vec![i, j]))
+        .collect();
+    for removals in [singles, pairs] {
+        for removed in removals {
+            let candidate: Vec<String> = args
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !removed.contains(i))
+                .map(|(_, arg)| arg.clone())
+                .collect();
+            let label = removed.iter().map(|&i| args[i].as_str()).collect::<Vec<_>>().join(" + ");
+            match bisect_probe(&candidate, port, domains, settings).await {
+                BisectProbe::Rate(rate) if rate >= BISECT_WORKING_RATE => {
+                    print_status("[+]", &format!("Без {}: {:.1}% - конфиг работает", label, rate));
+                    culprits.push((label, rate));
+                }
+                BisectProbe::Rate(rate) => {
+                    print_status("[~]", &format!("Без {}: {:.1}% - всё ещё не работает", label, rate));
+                }
+                BisectProbe::Inconclusive(reason) => {
+                    print_status("[WARN]", &format!("Без {}: не удалось проверить ({})", label, reason));
+                    inconclusive += 1;
+                }
+            }
+        }
+        if !culprits.is_empty() {
+            break;
+        }
+    }
+
+    print_section("РЕЗУЛЬТАТ BISECT");
+    if culprits.is_empty() {
+        console!("   Ни удаление одного флага, ни пары флагов не делает конфиг рабочим.");
+        console!("   Вероятно, домены блокируются независимо от этих флагов, или нужны другие флаги.");
+    } else {
+        console!("   Конфиг начинает работать, если убрать:");
+        for (label, rate) in &culprits {
+            console!("     {} ({:.1}%)", label, rate);
+        }
+    }
+    if inconclusive > 0 {
+        console!("   Вариантов, которые не удалось проверить (ciadpi не запустился): {}", inconclusive);
+    }
+}
+
+/// Splits a config into arguments, keeping separate values with their flag
+/// (`--split 2` stays one argument).
+fn split_config_args(config: &str) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    for token in config.split_whitespace() {
+        match args.last_mut() {
+            Some(last) if !token.starts_with('-') => {
+                last.push(' ');
+                last.push_str(token);
+            }
+            _ => args.push(token.to_string()),
+        }
+    }
+    args
+}
+
+/// Outcome of one bisect variant. A variant ciadpi can't run says nothing about
+/// the flags, so it is kept apart from a 0% rate.
+enum BisectProbe {
+    Rate(f32),
+    Inconclusive(String),
+}
+
+async fn bisect_probe(args: &[String], port: u16, domains: &[DomainSpec], settings: &Settings) -> BisectProbe {
+    let config = args.join(" ");
+    let mut process = match start_ciadpi_process(&config, port, None, settings).await {
+        Ok((process, _)) => ProcessGuard(process),
+        Err(e) => return BisectProbe::Inconclusive(format!("ciadpi не запустился: {}", e)),
+    };
+    time::sleep(ciadpi_start_delay(settings)).await;
+
+    if let Ok(Some(status)) = process.0.try_wait() {
+        return BisectProbe::Inconclusive(format!("ciadpi завершился сразу после запуска: {}", status));
+    }
+    let proxy = settings.proxy_address_family.proxy_address(port);
+    if tokio::net::TcpStream::connect(proxy).await.is_err() {
+        stop_process(&mut process.0);
+        return BisectProbe::Inconclusive(format!("ciadpi не слушает порт {}", port));
+    }
+
+    let (limiter, ramp) = create_request_limiter(settings, domains.len(), None);
+    let results =
+        test_all_domains(domains, port, settings, &limiter, &LiveCounters::default(), &ProgressBar::hidden()).await;
+    if let Some(ramp) = ramp {
+        ramp.abort();
+    }
+    match stop_process(&mut process.0) {
+        ProcessExit::Killed => {}
+        ProcessExit::Exited => return BisectProbe::Inconclusive("ciadpi завершился во время проверки".to_string()),
+        ProcessExit::Crashed(status) => {
+            return BisectProbe::Inconclusive(format!("ciadpi упал во время проверки: {}", status));
+        }
+    }
+
+    let total = results.successful.len() + results.failed.len();
+    if total > 0 {
+        BisectProbe::Rate(results.successful.len() as f32 / total as f32 * 100.0)
+    } else {
+        BisectProbe::Inconclusive("нет проверенных доменов".to_string())
+    }
+}
+
+async fn spot_request(
+    client: &reqwest::Client,
+    domain: &DomainSpec,
+    scheme: Scheme,
+    settings: &Settings,
+) -> bool {
+    const BODY_PREVIEW: usize = 512;
+
+    let url = domain.url(scheme);
+
+    console!();
+    console!("   > {} {}", domain.method, url);
+    if scheme == Scheme::Https && domain.sni.is_some() {
+        console!("   > Host: {}", domain.authority());
+    }
+    if let Some(body) = &domain.body {
+        console!("   > Content-Type: {}", domain.content_type);
+        console!("   > {}", body);
+    }
+    let request = domain.request(client, scheme);
+
+    let started = std::time::Instant::now();
+    let resp = match request.send().await {
+        Ok(resp) => resp,
+        Err(e) => {
+            print_status(
+                "[FAIL]",
+                &format!(
+                    "{} через {} мс: {}",
+                    FailureKind::from_error(&e).label(),
+                    started.elapsed().as_millis(),
+                    e
+                ),
+            );
+            return false;
+        }
+    };
+
+    let status = resp.status();
+    console!("   < {:?} {}", resp.version(), status);
+    for (name, value) in resp.headers() {
+        console!("   < {}: {}", name, value.to_str().unwrap_or("<binary>"));
+    }
+    let headers_ms = started.elapsed().as_millis();
+    let has_headers = settings.rules.accepts_headers(resp.headers());
+
+    let body = resp.bytes().await.unwrap_or_default();
+    console!();
+    console!(
+        "   Заголовки получены за {} мс, тело ({} байт) за {} мс",
+        headers_ms,
+        body.len(),
+        started.elapsed().as_millis()
+    );
+    let preview = String::from_utf8_lossy(&body[..body.len().min(BODY_PREVIEW)]);
+    for line in preview.lines() {
+        console!("   | {}", line);
+    }
+
+    let accepted = is_expected_status(status, domain, settings);
+    print_status(if accepted { "[OK]" } else { "[FAIL]" }, &format!("Статус {}", status));
+    if !settings.rules.require_headers.is_empty() {
+        let label = if has_headers { "[OK]" } else { "[FAIL]" };
+        print_status(label, &format!("Заголовки {}", settings.rules.require_headers.join(", ")));
+    }
+    let forbidden = settings.rules.forbidden_match(&body);
+    if let Some(marker) = forbidden {
+        print_status("[FAIL]", &format!("Тело содержит признак блокировки '{}'", marker));
+    }
+    accepted && has_headers && forbidden.is_none()
+}
+
+fn ciadpi_executable() -> &'static str {
+    if cfg!(windows) { "ciadpi.exe" } else { "./ciadpi" }
+}
+
+async fn query_ciadpi_output(arg: &str) -> Option<String> {
+    let output = tokio::process::Command::new(ciadpi_executable())
+        .arg(arg)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = time::timeout(Duration::from_secs(3), output).await.ok()?.ok()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Some(text)
+}
+
+fn extract_help_flags(help: &str) -> HashSet<String> {
+    let flag_pattern = regex::Regex::new(r"(?:^|[\s,\[])(--[a-z][a-z0-9-]*|-[A-Za-z])\b")
+        .expect("valid flag pattern");
+    flag_pattern
+        .captures_iter(help)
+        .map(|c| c[1].to_string())
+        .collect()
+}
+
+fn config_flags(config: &str) -> Vec<String> {
+    config
+        .split_whitespace()
+        .filter_map(|arg| {
+            if let Some(long) = arg.strip_prefix("--") {
+                let name = long.split('=').next().unwrap_or(long);
+                Some(format!("--{}", name))
+            } else {
+                let mut chars = arg.chars();
+                match (chars.next(), chars.next()) {
+                    (Some('-'), Some(c)) if c.is_ascii_alphabetic() => Some(format!("-{}", c)),
+                    _ => None,
+                }
+            }
+        })
+        .collect()
+}
+
+async fn check_ciadpi_compatibility(configs: &[String], settings: &Settings) {
+    let Some(help) = query_ciadpi_output("--help").await else {
+        print_status("[WARN]", "Не удалось получить список опций ciadpi, проверка совместимости пропущена");
+        return;
+    };
+
+    let known_flags = extract_help_flags(&help);
+    if known_flags.is_empty() {
+        print_status("[WARN]", "ciadpi не вывел список опций, проверка совместимости пропущена");
+        return;
+    }
+
+    if let Some(version) = query_ciadpi_output("--version").await {
+        if let Some(line) = version.lines().map(str::trim).find(|l| !l.is_empty()) {
+            print_status("[+]", &format!("Версия ciadpi: {}", line));
+        }
+    }
+
+    let mut warnings = 0;
+    for config in configs {
+        let unknown: Vec<String> = config_flags(config)
+            .into_iter()
+            .filter(|flag| !known_flags.contains(flag))
+            .collect();
+
+        if !unknown.is_empty() {
+            warnings += 1;
+            print_status(
+                "[WARN]",
+                &format!(
+                    "{}: ciadpi не поддерживает {}",
+                    display_config(config, settings),
+                    unknown.join(", ")
+                ),
+            );
+        }
+    }
+
+    if warnings == 0 {
+        print_status("[+]", "Все флаги конфигов поддерживаются установленной версией ciadpi");
+    }
+    console!();
+}
+
+/// Starts ciadpi and waits for it to come up. With `bind_retries` set, a
+/// ciadpi that never starts listening (usually the port is still held by the
+/// OS) is restarted on a reserve port.
+async fn start_ciadpi_bound(
+    config: &str,
+    socks5_port: u16,
+    group_dir: Option<&Path>,
+    settings: &Settings,
+    group: &GroupContext,
+) -> Result<(Child, Option<PathBuf>, u16), Box<dyn std::error::Error + Send + Sync>> {
+    let mut port = socks5_port;
+    let mut attempt = 0;
+
+    loop {
+        let (mut process, log_path) = start_ciadpi_process(config, port, group_dir, settings).await?;
+        if settings.port_rebind_min_interval_ms > 0 {
+            group
+                .port_tracker
+                .bind(port, Duration::from_millis(settings.port_rebind_min_interval_ms));
+        }
+        time::sleep(ciadpi_start_delay(settings)).await;
+
+        let exited = matches!(process.try_wait(), Ok(Some(_)));
+        if attempt >= settings.bind_retries || (!exited && is_listening(port, settings).await) {
+            return Ok((process, log_path, port));
+        }
+
+        stop_process(&mut process);
+        group.port_tracker.release(port);
+        attempt += 1;
+        let Some(next) = group.take_reserve_port() else {
+            return Err(format!("ciadpi ({}) did not bind port {} and no reserve port is free", config, port).into());
+        };
+        print_status(
+            "[~]",
+            &format!(
+                "{}: ciadpi не открыл порт {}, повтор {}/{} на порту {}",
+                extract_config_name(config),
+                port,
+                attempt,
+                settings.bind_retries,
+                next
+            ),
+        );
+        port = next;
+    }
+}
+
+async fn is_listening(port: u16, settings: &Settings) -> bool {
+    let address = settings.proxy_address_family.proxy_address(port);
+    tokio::net::TcpStream::connect(address).await.is_ok()
+}
+
+async fn start_ciadpi_process(
+    config: &str,
+    socks5_port: u16,
+    group_dir: Option<&Path>,
+    settings: &Settings,
+) -> Result<(Child, Option<PathBuf>), Box<dyn std::error::Error + Send + Sync>> {
+    let exe_name = ciadpi_executable();
+    let (log_file, log_path) = open_ciadpi_log(config, socks5_port, group_dir)?.unzip();
+    let mut delay = Duration::from_millis(settings.spawn_retry_delay_ms);
+    let mut attempt = 0;
+
+    loop {
+        match spawn_ciadpi(config, socks5_port, settings, log_file.as_ref()) {
+            Ok(child) => return Ok((child, log_path)),
+            Err(e) if attempt < settings.spawn_retries && is_transient_spawn_error(&e) => {
+                attempt += 1;
+                print_status(
+                    "[~]",
+                    &format!(
+                        "{}: временная ошибка запуска ({}), повтор {}/{}",
+                        extract_config_name(config),
+                        e,
+                        attempt,
+                        settings.spawn_retries
+                    ),
+                );
+                time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => {
+                return Err(format!("Failed to spawn {} ({}): {}", exe_name, config, e).into())
+            }
+        }
+    }
+}
+
+fn spawn_ciadpi(
+    config: &str,
+    socks5_port: u16,
+    settings: &Settings,
+    log_file: Option<&File>,
+) -> std::io::Result<Child> {
+    let exe_name = ciadpi_executable();
+    let (stdout, stderr) = match log_file {
+        Some(file) => (Stdio::from(file.try_clone()?), Stdio::from(file.try_clone()?)),
+        None => (Stdio::null(), Stdio::null()),
+    };
+
+    let args: Vec<&str> = config.split_whitespace().collect();
+    let port = socks5_port.to_string();
+    let fixed_args = settings
+        .fixed_ciadpi_args
+        .iter()
+        .map(|arg| arg.replace("{port}", &port));
+
+    let mut command = Command::new(exe_name);
+    command
+        .args(&args)
+        .args(&settings.extra_ciadpi_args)
+        .args(fixed_args)
+        .stdout(stdout)
+        .stderr(stderr);
+    spawn_unpinned(command)
+}
+
+/// Restricts the tokio worker threads to the `cpu_affinity` cores. Best-effort:
+/// unknown core IDs are skipped, and on platforms without affinity support
+/// nothing changes.
+fn configure_cpu_affinity(cores: &[usize]) {
+    if cores.is_empty() {
+        return;
+    }
+
+    let available = core_affinity::get_core_ids().unwrap_or_default();
+    let (usable, unknown): (Vec<usize>, Vec<usize>) =
+        cores.iter().partition(|id| available.iter().any(|core| core.id == **id));
+    if !unknown.is_empty() {
+        print_status("[WARN]", &format!("cpu_affinity: ядра {:?} недоступны и пропущены", unknown));
+    }
+    if usable.is_empty() {
+        print_status("[WARN]", "cpu_affinity: ни одно ядро недоступно, привязка к ядрам не выполняется");
+        return;
+    }
+
+    // ciadpi is started from a thread created before pinning, so it doesn't
+    // inherit the tester's cores.
+    let (sender, requests) = std::sync::mpsc::channel::<LaunchRequest>();
+    std::thread::spawn(move || {
+        for (mut command, reply) in requests {
+            let _ = reply.send(command.spawn());
+        }
+    });
+    let _ = PROCESS_LAUNCHER.set(sender);
+
+    let _ = CPU_AFFINITY.set(usable.iter().map(|&id| core_affinity::CoreId { id }).collect());
+    pin_current_thread();
+    print_status("[+]", &format!("Потоки тестера привязаны к ядрам {:?}", usable));
+}
+
+/// Pins the calling thread to the next `cpu_affinity` core. Called from the
+/// runtime hooks, so every worker pins itself on its first wake-up.
+fn pin_current_thread() {
+    let Some(cores) = CPU_AFFINITY.get() else {
+        return;
+    };
+    if THREAD_PINNED.with(|pinned| pinned.replace(true)) {
+        return;
+    }
+    let core = cores[NEXT_AFFINITY_CORE.fetch_add(1, Ordering::Relaxed) % cores.len()];
+    core_affinity::set_for_current(core);
+}
+
+fn spawn_unpinned(mut command: Command) -> std::io::Result<Child> {
+    let Some(launcher) = PROCESS_LAUNCHER.get() else {
+        return command.spawn();
+    };
+
+    let (reply, response) = std::sync::mpsc::channel();
+    launcher
+        .send((command, reply))
+        .map_err(|_| std::io::Error::other("process launcher thread is gone"))?;
+    response
+        .recv()
+        .map_err(|_| std::io::Error::other("process launcher thread is gone"))?
+}
+
+fn is_transient_spawn_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::OutOfMemory
+            | std::io::ErrorKind::ResourceBusy
+    )
+}
+
+fn open_ciadpi_log(
+    config: &str,
+    socks5_port: u16,
+    group_dir: Option<&Path>,
+) -> Result<Option<(File, PathBuf)>, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(group_dir) = group_dir else {
+        return Ok(None);
+    };
+
+    let log_file_name = sanitize_filename(config, socks5_port);
+    let log_path = group_dir.join(format!("ciadpi_{}.log", log_file_name));
+    
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+
+    Ok(Some((log_file, log_path)))
+}
+
+/// Picks the most telling error line ciadpi wrote to its log, checking the
+/// markers in order so bind and argument errors win over generic ones.
+fn extract_ciadpi_error(log_path: &Path) -> Option<String> {
+    ciadpi_error_line(&std::fs::read_to_string(log_path).ok()?)
+}
+
+/// The line of a ciadpi log that explains why it didn't start: a perror-style
+/// failure of the listening socket or an option ciadpi rejected. Per-connection
+/// errors ciadpi logs while running are ignored.
+fn ciadpi_error_line(log: &str) -> Option<String> {
+    const FAILED_CALLS: [&str; 3] = ["bind:", "listen:", "socket:"];
+    const MESSAGES: [&str; 6] = [
+        "address already in use",
+        "invalid value",
+        "invalid option",
+        "unrecognized option",
+        "unknown option",
+        "usage:",
+    ];
+    const MAX_REASON_LEN: usize = 160;
+
+    let lines: Vec<&str> = log.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    let lowered: Vec<String> = lines.iter().map(|line| line.to_lowercase()).collect();
+    let index = FAILED_CALLS
+        .iter()
+        .find_map(|call| lowered.iter().position(|line| line.starts_with(call)))
+        .or_else(|| {
+            MESSAGES
+                .iter()
+                .find_map(|message| lowered.iter().position(|line| line.contains(message)))
+        })?;
+    Some(lines[index].chars().take(MAX_REASON_LEN).collect())
+}
+
+fn discard_unneeded_log(log_path: &Path, keep_logs: KeepLogs, fully_succeeded: bool) {
+    let keep = match keep_logs {
+        KeepLogs::All => true,
+        KeepLogs::Failed => !fully_succeeded,
+        KeepLogs::None => false,
+    };
+
+    if !keep {
+        let _ = std::fs::remove_file(log_path);
+    }
+}
+
+/// Kills ciadpi when its owner is dropped or returns early, e.g. on an error,
+/// before `stop_process`.
+struct ProcessGuard(Child);
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
+
+fn stop_process(child: &mut Child) -> ProcessExit {
+    match child.try_wait() {
+        Ok(Some(status)) if status.success() => ProcessExit::Exited,
+        Ok(Some(status)) => ProcessExit::Crashed(status.to_string()),
+        _ => {
+            let _ = child.kill();
+            let _ = child.wait();
+            ProcessExit::Killed
+        }
+    }
+}
+
+async fn test_all_domains(
+    domains: &[DomainSpec],
+    port: u16,
+    settings: &Settings,
+    limiter: &Semaphore,
+    counters: &LiveCounters,
+    progress: &ProgressBar,
+) -> DomainResults {
+    let config_limiter = Semaphore::new(
+        settings
+            .per_config_concurrency
+            .unwrap_or(domains.len())
+            .clamp(1, Semaphore::MAX_PERMITS),
+    );
+
+    let tasks: Vec<_> = domains
+        .iter()
+        .map(|domain| async {
+            let outcome = test_domain_repeated(domain, port, settings, &config_limiter, limiter).await;
+            match outcome.failure {
+                None => counters.record(true),
+                Some(kind) if is_counted_failure(kind, settings) => counters.record(false),
+                Some(_) => {}
+            }
+            progress.inc(1);
+            outcome
+        })
+        .collect();
+
+    let outcomes = futures::future::join_all(tasks).await;
+    let mut results = DomainResults::default();
+
+    for outcome in outcomes {
+        results.bytes_received += outcome.bytes;
+        results.attempts_successful += outcome.successes;
+        results.attempts_total += outcome.attempts;
+        results.latencies_ms.extend(outcome.latencies_ms);
+        match outcome.http3 {
+            Some(true) => results.http3_successful.push(outcome.domain.clone()),
+            Some(false) => results.http3_failed.push(outcome.domain.clone()),
+            None => {}
+        }
+        match outcome.failure {
+            None => {
+                if outcome.www_variant {
+                    results.www_variant.push(outcome.domain.clone());
+                }
+                if let Some(ip) = outcome.resolved_ip {
+                    results.resolved_ips.insert(outcome.domain.clone(), ip.to_string());
+                }
+                results.successful.push(outcome.domain);
+            }
+            Some(kind) if is_counted_failure(kind, settings) => {
+                results.failure_reasons.insert(outcome.domain.clone(), kind);
+                results.failed.push(outcome.domain);
+            }
+            Some(_) => results.excluded.push(outcome.domain),
+        }
+    }
+
+    results
+}
+
+/// Upper bound for one domain check: every HTTPS attempt and the HTTP fallback
+/// may each use the full client timeout, anything beyond that means the task is stuck.
+fn domain_watchdog(settings: &Settings) -> Duration {
+    const WATCHDOG_MARGIN_SEC: u64 = 2;
+    let requests = u64::from(settings.https_attempts_before_fallback.max(1)) + 1;
+    Duration::from_secs(settings.request_timeout_sec * requests + WATCHDOG_MARGIN_SEC)
+}
+
+fn validate_domain_success_quorum(
+    quorum: DomainSuccessQuorum,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let split = matches!(quorum, DomainSuccessQuorum::Split { .. });
+    if !split && settings.repeat_count <= 1 && settings.suite_repeats <= 1 {
+        return Err("domain_success_quorum requires repeat_count or suite_repeats above 1".into());
+    }
+    let parts = [
+        ("attempts", quorum.attempts(), "repeat_count", settings.repeat_count),
+        ("runs", quorum.runs(), "suite_repeats", settings.suite_repeats),
+    ];
+    for (part, value, name, count) in parts {
+        let Some(value) = value else {
+            continue;
+        };
+        if value == 0 {
+            return Err("domain_success_quorum must be at least 1".into());
+        }
+        if split && count <= 1 {
+            return Err(format!("domain_success_quorum.{} requires {} above 1", part, name).into());
+        }
+        if count > 1 && value > count {
+            return Err(format!("domain_success_quorum {} exceeds {} {}", value, name, count).into());
+        }
+    }
+    Ok(())
+}
+
+fn validate_tls_fingerprint(settings: &Settings) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if settings.tls_fingerprint == TlsFingerprint::Default {
+        return Ok(());
+    }
+    if !cfg!(feature = "browser-tls") {
+        return Err(format!(
+            "tls_fingerprint = \"{}\" requires a build with --features browser-tls",
+            settings.tls_fingerprint.name()
+        )
+        .into());
+    }
+    if settings.tcp_connect_only {
+        print_status("[WARN]", "tls_fingerprint не используется: включён tcp_connect_only");
+    }
+    Ok(())
+}
+
+fn validate_http3(settings: &Settings) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !settings.enable_http3 {
+        return Ok(());
+    }
+    if !cfg!(feature = "http3") {
+        return Err("enable_http3 requires a build with --features http3".into());
+    }
+    if settings.tcp_connect_only {
+        print_status("[WARN]", "enable_http3 не используется: включён tcp_connect_only");
+    }
+    Ok(())
+}
+
+fn is_counted_failure(kind: FailureKind, settings: &Settings) -> bool {
+    match kind {
+        FailureKind::Timeout => settings.count_timeouts,
+        FailureKind::Connect => settings.count_connect_errors,
+        FailureKind::Rejected | FailureKind::ProxyAuth | FailureKind::Hung | FailureKind::Other => true,
+    }
+}
+
+async fn test_domain_repeated(
+    domain: &DomainSpec,
+    port: u16,
+    settings: &Settings,
+    config_limiter: &Semaphore,
+    limiter: &Semaphore,
+) -> DomainOutcome {
+    let mut outcome = test_domain(domain.clone(), port, settings, config_limiter, limiter).await;
+    let mut last_failure = outcome.failure;
+
+    for _ in 1..settings.repeat_count.max(1) {
+        let next = test_domain(domain.clone(), port, settings, config_limiter, limiter).await;
+        outcome.attempts += next.attempts;
+        outcome.successes += next.successes;
+        outcome.bytes += next.bytes;
+        outcome.latencies_ms.extend(next.latencies_ms);
+        outcome.www_variant |= next.www_variant;
+        outcome.resolved_ip = outcome.resolved_ip.or(next.resolved_ip);
+        outcome.http3 = match (outcome.http3, next.http3) {
+            (Some(first), Some(second)) => Some(first || second),
+            (first, second) => first.or(second),
+        };
+        last_failure = next.failure.or(last_failure);
+    }
+
+    outcome.failure = if attempts_passed(outcome.successes, outcome.attempts, settings) {
+        None
+    } else {
+        last_failure.or(Some(FailureKind::Other))
+    };
+    outcome
+}
+
+/// Whether a domain counts as reachable after `repeat_count` attempts: the
+/// attempts quorum when one is set, a strict majority otherwise.
+fn attempts_passed(successes: usize, attempts: usize, settings: &Settings) -> bool {
+    match settings.domain_success_quorum.and_then(|quorum| quorum.attempts()) {
+        Some(quorum) if settings.repeat_count > 1 => successes >= quorum as usize,
+        _ => successes * 2 > attempts,
+    }
+}
+
+async fn test_domain(
+    domain: DomainSpec,
+    port: u16,
+    settings: &Settings,
+    config_limiter: &Semaphore,
+    limiter: &Semaphore,
+) -> DomainOutcome {
+    let _config_permit = config_limiter.acquire().await;
+    let _permit = limiter.acquire().await;
+
+    let started = std::time::Instant::now();
+    let mut result = parallel_check(&domain, port, settings).await;
+    let mut www_variant = None;
+    if result.is_err() && settings.try_www_variant {
+        if let Some(variant) = domain.www_variant() {
+            let retry = parallel_check(&variant, port, settings).await;
+            if retry.is_ok() {
+                result = retry;
+                www_variant = Some(variant);
+            }
+        }
+    }
+    let elapsed = started.elapsed();
+
+    let h3_port = result.as_ref().ok().and_then(|check| check.h3_port);
+    let http3 = if settings.enable_http3 && !settings.tcp_connect_only {
+        http3_check(www_variant.as_ref().unwrap_or(&domain), port, h3_port, settings).await
+    } else {
+        None
+    };
+    let resolved_ip = result.as_ref().ok().and_then(|check| check.resolved_ip);
+    let mut outcome = DomainOutcome::from_result(domain.name, result.map(|check| check.bytes), elapsed);
+    outcome.www_variant = www_variant.is_some();
+    outcome.resolved_ip = resolved_ip;
+    outcome.http3 = http3;
+    outcome
+}
+
+/// First address the system resolver returns for `host`, as reqwest would use
+/// for a `socks5://` proxy.
+async fn resolve_ip(host: &str, port: u16) -> Option<std::net::IpAddr> {
+    tokio::net::lookup_host((host, port))
+        .await
+        .ok()?
+        .next()
+        .map(|addr| addr.ip())
+}
+
+/// Whether an HTTP/3 request to `domain` gets through ciadpi, sent to the port
+/// the passed HTTPS check saw in Alt-Svc. `None` when the response didn't
+/// advertise h3, so domains without HTTP/3 are left out instead of failing.
+async fn http3_check(domain: &DomainSpec, port: u16, h3_port: Option<u16>, settings: &Settings) -> Option<bool> {
+    let h3_port = h3_port?;
+
+    let proxy = settings.proxy_address_family.proxy_address(port);
+    let timeout = Duration::from_secs(settings.request_timeout_sec);
+    let result = time::timeout(timeout, http3_request(proxy, domain, h3_port)).await;
+    Some(matches!(result, Ok(Ok(()))))
+}
+
+fn advertised_h3_port(headers: &reqwest::header::HeaderMap) -> Option<u16> {
+    headers
+        .get_all(reqwest::header::ALT_SVC)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find_map(alt_svc_h3_port)
+}
+
+/// Port of the `h3` entry in an Alt-Svc value such as `h3=":443"; ma=86400`.
+/// An alternative host in the entry is ignored, the request goes to the domain itself.
+fn alt_svc_h3_port(value: &str) -> Option<u16> {
+    value.split(',').find_map(|entry| {
+        let (protocol, authority) = entry.split(';').next()?.trim().split_once('=')?;
+        if protocol != "h3" {
+            return None;
+        }
+        authority.trim_matches('"').rsplit_once(':')?.1.parse().ok()
+    })
+}
+
+#[cfg(not(feature = "http3"))]
+async fn http3_request(
+    _proxy: std::net::SocketAddr,
+    _domain: &DomainSpec,
+    _port: u16,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Err("enable_http3 requires a build with --features http3".into())
+}
+
+/// One HTTP/3 GET over QUIC, with the UDP datagrams relayed through the SOCKS5
+/// UDP ASSOCIATE of ciadpi. Any response counts: the request got past DPI.
+#[cfg(feature = "http3")]
+async fn http3_request(
+    proxy: std::net::SocketAddr,
+    domain: &DomainSpec,
+    port: u16,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let host = domain.connect_host();
+    let ip = resolve_ip(host, port).await.ok_or("failed to resolve")?;
+    let target = std::net::SocketAddr::new(ip, port);
+    let socket = SocksUdpSocket::associate(proxy).await?;
+    let mut endpoint = quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        None,
+        Arc::new(socket),
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    endpoint.set_default_client_config(http3_client_config()?);
+
+    let connection = endpoint.connect(target, host)?.await?;
+    let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+    let driver = tokio::spawn(async move { futures::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let path = domain.https_path.as_deref().unwrap_or(&domain.path);
+    let uri = format!("https://{}{}", domain.authority(), if path.is_empty() { "/" } else { path });
+    let result = async {
+        let mut stream = sender.send_request(http::Request::get(uri).body(())?).await?;
+        stream.finish().await?;
+        stream.recv_response().await?;
+        Ok(())
+    }
+    .await;
+    driver.abort();
+    endpoint.close(0u32.into(), b"");
+    result
+}
+
+#[cfg(feature = "http3")]
+fn http3_client_config() -> Result<quinn::ClientConfig, Box<dyn std::error::Error + Send + Sync>> {
+    use quinn::rustls;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)?;
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+/// UDP socket for quinn that wraps every datagram in the SOCKS5 UDP header
+/// and sends it to the relay ciadpi opened. The association lives as long as
+/// the TCP control connection, which the socket keeps open.
+#[cfg(feature = "http3")]
+#[derive(Debug)]
+struct SocksUdpSocket {
+    io: tokio::net::UdpSocket,
+    relay: std::net::SocketAddr,
+    /// Holds a datagram with its SOCKS5 header until the payload is copied out.
+    recv_buf: std::sync::Mutex<Vec<u8>>,
+    _control: tokio::net::TcpStream,
+}
+
+#[cfg(feature = "http3")]
+impl SocksUdpSocket {
+    async fn associate(proxy: std::net::SocketAddr) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut control = socks5_greet(proxy).await.map_err(|kind| format!("SOCKS5 greeting failed: {:?}", kind))?;
+        control.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+        let mut reply = [0u8; 4];
+        control.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(format!("UDP ASSOCIATE rejected with code {}", reply[1]).into());
+        }
+        let ip = match reply[3] {
+            0x01 => {
+                let mut octets = [0u8; 4];
+                control.read_exact(&mut octets).await?;
+                std::net::IpAddr::from(octets)
+            }
+            0x04 => {
+                let mut octets = [0u8; 16];
+                control.read_exact(&mut octets).await?;
+                std::net::IpAddr::from(octets)
+            }
+            _ => return Err("UDP ASSOCIATE replied with an unsupported address type".into()),
+        };
+        let port = control.read_u16().await?;
+        // An unspecified relay address means "the address you reached the proxy at".
+        let relay = std::net::SocketAddr::new(if ip.is_unspecified() { proxy.ip() } else { ip }, port);
+
+        let local: std::net::SocketAddr = match relay {
+            std::net::SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+            std::net::SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let io = tokio::net::UdpSocket::bind(local).await?;
+        Ok(Self {
+            io,
+            relay,
+            recv_buf: std::sync::Mutex::new(vec![0; 65535]),
+            _control: control,
+        })
+    }
+}
+
+#[cfg(feature = "http3")]
+impl quinn::AsyncUdpSocket for SocksUdpSocket {
+    fn create_io_poller(self: Arc<Self>) -> std::pin::Pin<Box<dyn quinn::UdpPoller>> {
+        Box::pin(SocksUdpPoller(self))
+    }
+
+    fn try_send(&self, transmit: &quinn::udp::Transmit) -> std::io::Result<()> {
+        let datagram = socks_udp_datagram(transmit.destination, transmit.contents);
+        self.io.try_send_to(&datagram, self.relay).map(|_| ())
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut std::task::Context,
+        bufs: &mut [std::io::IoSliceMut<'_>],
+        meta: &mut [quinn::udp::RecvMeta],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let mut datagram = self.recv_buf.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        loop {
+            std::task::ready!(self.io.poll_recv_ready(cx))?;
+            let (len, from) = match self.io.try_recv_from(&mut datagram) {
+                Ok(received) => received,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(e) => return std::task::Poll::Ready(Err(e)),
+            };
+            // Datagrams not from the relay, fragmented ones and malformed headers are dropped.
+            let Some((source, payload)) = (from == self.relay)
+                .then(|| parse_socks_udp_header(&datagram[..len]))
+                .flatten()
+            else {
+                continue;
+            };
+            let copied = payload.len().min(bufs[0].len());
+            bufs[0][..copied].copy_from_slice(&payload[..copied]);
+            meta[0].addr = source;
+            meta[0].len = copied;
+            meta[0].stride = copied;
+            meta[0].ecn = None;
+            meta[0].dst_ip = None;
+            return std::task::Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.io.local_addr()
+    }
+}
+
+/// `payload` behind the SOCKS5 UDP header addressed to `destination`.
+#[cfg(feature = "http3")]
+fn socks_udp_datagram(destination: std::net::SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x00, 0x00, 0x00];
+    match destination {
+        std::net::SocketAddr::V4(addr) => {
+            datagram.push(0x01);
+            datagram.extend_from_slice(&addr.ip().octets());
+        }
+        std::net::SocketAddr::V6(addr) => {
+            datagram.push(0x04);
+            datagram.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    datagram.extend_from_slice(&destination.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Source address and payload of a SOCKS5 UDP datagram.
+#[cfg(feature = "http3")]
+fn parse_socks_udp_header(datagram: &[u8]) -> Option<(std::net::SocketAddr, &[u8])> {
+    let (ip, rest): (std::net::IpAddr, _) = match datagram.get(..4)? {
+        [0x00, 0x00, 0x00, 0x01] => {
+            let octets: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (octets.into(), &datagram[8..])
+        }
+        [0x00, 0x00, 0x00, 0x04] => {
+            let octets: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (octets.into(), &datagram[20..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((std::net::SocketAddr::new(ip, port), &rest[2..]))
+}
+
+#[cfg(feature = "http3")]
+#[derive(Debug)]
+struct SocksUdpPoller(Arc<SocksUdpSocket>);
+
+#[cfg(feature = "http3")]
+impl quinn::UdpPoller for SocksUdpPoller {
+    fn poll_writable(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.0.io.poll_send_ready(cx)
+    }
+}
+
+/// Opens `connections_per_domain` simultaneous connections to the domain; the
+/// check passes when at least `connections_success_fraction` of them succeed.
+async fn parallel_check(domain: &DomainSpec, port: u16, settings: &Settings) -> Result<TracedCheck, FailureKind> {
+    let connections = settings.connections_per_domain.max(1);
+    if connections == 1 {
+        return watched_check(domain, port, settings).await;
+    }
+
+    let checks = (0..connections).map(|_| watched_check(domain, port, settings));
+    let results = futures::future::join_all(checks).await;
+    let passed: Vec<TracedCheck> = results.iter().filter_map(|result| result.as_ref().ok().copied()).collect();
+    if passed.len() as f32 >= connections as f32 * settings.connections_success_fraction {
+        Ok(TracedCheck {
+            bytes: passed.iter().map(|check| check.bytes).sum(),
+            resolved_ip: passed.iter().find_map(|check| check.resolved_ip),
+            h3_port: passed.iter().find_map(|check| check.h3_port),
+        })
+    } else {
+        Err(results.into_iter().find_map(Result::err).unwrap_or(FailureKind::Other))
+    }
+}
+
+async fn watched_check(domain: &DomainSpec, port: u16, settings: &Settings) -> Result<TracedCheck, FailureKind> {
+    let watchdog = domain_watchdog(settings);
+    match time::timeout(watchdog, check_domain_traced(domain, Some(port), settings)).await {
+        Ok(result) => result,
+        Err(_) => {
+            print_status(
+                "[WARN]",
+                &format!("{}: проверка зависла дольше {} с и брошена", domain.name, watchdog.as_secs()),
+            );
+            Err(FailureKind::Hung)
+        }
+    }
+}
+
+/// What a passed check saw besides the bytes received: the IP the tested
+/// connection went to when `local_dns` is on, and the HTTP/3 port the HTTPS
+/// response advertised in Alt-Svc.
+#[derive(Debug, Clone, Copy, Default)]
+struct TracedCheck {
+    bytes: u64,
+    resolved_ip: Option<std::net::IpAddr>,
+    h3_port: Option<u16>,
+}
+
+/// Bytes received, plus the HTTP/3 port the response advertised in Alt-Svc.
+type HttpsCheck = (u64, Option<u16>);
+
+async fn check_domain(
+    domain: &DomainSpec,
+    proxy_port: Option<u16>,
+    settings: &Settings,
+) -> Result<u64, FailureKind> {
+    check_domain_traced(domain, proxy_port, settings)
+        .await
+        .map(|check| check.bytes)
+}
+
+async fn check_domain_traced(
+    domain: &DomainSpec,
+    proxy_port: Option<u16>,
+    settings: &Settings,
+) -> Result<TracedCheck, FailureKind> {
+    if settings.tcp_connect_only {
+        let timeout = Duration::from_secs(settings.request_timeout_sec);
+        let target_port = domain.port.unwrap_or(Scheme::Https.default_port());
+        let connect = async {
+            match proxy_port {
+                Some(port) => {
+                    let proxy = settings.proxy_address_family.proxy_address(port);
+                    if !settings.local_dns {
+                        return socks5_connect(proxy, domain.connect_host(), target_port)
+                            .await
+                            .map(|_| None);
+                    }
+                    let ip = resolve_ip(domain.connect_host(), target_port)
+                        .await
+                        .ok_or(FailureKind::Connect)?;
+                    socks5_connect(proxy, &ip.to_string(), target_port).await.map(|_| Some(ip))
+                }
+                None => tokio::net::TcpStream::connect((domain.connect_host(), target_port))
+                    .await
+                    .map(|_| None)
+                    .map_err(|_| FailureKind::Connect),
+            }
+        };
+        return time::timeout(timeout, connect)
+            .await
+            .unwrap_or(Err(FailureKind::Timeout))
+            .map(|resolved_ip| TracedCheck {
+                resolved_ip,
+                ..TracedCheck::default()
+            });
+    }
+
+    let local_dns_proxy = match proxy_port {
+        Some(port) if settings.local_dns => {
+            let upstream = settings.proxy_address_family.proxy_address(port);
+            Some(LocalDnsProxy::start(upstream).await.map_err(|_| FailureKind::Other)?)
+        }
+        _ => None,
+    };
+    let client = match &local_dns_proxy {
+        Some(front) => build_http_client(Some(&format!("socks5h://{}", front.address)), settings),
+        None => create_http_client(proxy_port, settings),
+    }
+    .map_err(|_| FailureKind::Other)?;
+    let proxy = match &local_dns_proxy {
+        Some(front) => Some(front.address),
+        None => proxy_port.map(|port| settings.proxy_address_family.proxy_address(port)),
+    };
+
+    let (bytes, h3_port) = check_with_client(&client, proxy, domain, settings).await?;
+    let resolved_ip = local_dns_proxy.and_then(|front| front.resolved_ip(domain.connect_host()));
+    Ok(TracedCheck {
+        bytes,
+        resolved_ip,
+        h3_port,
+    })
+}
+
+/// `proxy` is the SOCKS5 address `client` goes through, for HTTPS requests that
+/// `tls_fingerprint` sends outside of reqwest.
+async fn check_with_client(
+    client: &reqwest::Client,
+    proxy: Option<std::net::SocketAddr>,
+    domain: &DomainSpec,
+    settings: &Settings,
+) -> Result<HttpsCheck, FailureKind> {
+    match domain.scheme {
+        Some(Scheme::Https) => try_https(client, proxy, domain, settings).await,
+        Some(Scheme::Http) => try_http(client, domain, settings).await.map(|bytes| (bytes, None)),
+        None if settings.require_both_schemes => {
+            let https = try_https(client, proxy, domain, settings).await;
+            let http = try_http(client, domain, settings).await;
+            https.and_then(|(https_bytes, h3_port)| http.map(|http_bytes| (https_bytes + http_bytes, h3_port)))
+        }
+        None => {
+            let mut https = try_https(client, proxy, domain, settings).await;
+            for _ in 1..settings.https_attempts_before_fallback.max(1) {
+                if https.is_ok() {
+                    break;
+                }
+                https = try_https(client, proxy, domain, settings).await;
+            }
+            match https {
+                Ok(passed) => Ok(passed),
+                Err(e) if domain.sni.is_some() => Err(e),
+                Err(_) => try_http(client, domain, settings).await.map(|bytes| (bytes, None)),
+            }
+        }
+    }
+}
+
+async fn socks5_connect(
+    proxy: std::net::SocketAddr,
+    host: &str,
+    target_port: u16,
+) -> Result<tokio::net::TcpStream, FailureKind> {
+    let mut stream = socks5_greet(proxy).await?;
+    socks5_request(&mut stream, host, target_port).await?;
+    Ok(stream)
+}
+
+/// Connects to the proxy and negotiates the no-auth method.
+async fn socks5_greet(proxy: std::net::SocketAddr) -> Result<tokio::net::TcpStream, FailureKind> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::TcpStream::connect(proxy)
+        .await
+        .map_err(|_| FailureKind::Connect)?;
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await.map_err(|_| FailureKind::Connect)?;
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await.map_err(|_| FailureKind::Connect)?;
+    if greeting != [0x05, 0x00] {
+        return Err(FailureKind::ProxyAuth);
+    }
+    Ok(stream)
+}
+
+/// Sends a CONNECT request and reads the whole reply, leaving the stream at
+/// the start of the tunnelled data.
+async fn socks5_request(stream: &mut tokio::net::TcpStream, host: &str, target_port: u16) -> Result<(), FailureKind> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let host_len = u8::try_from(host.len()).map_err(|_| FailureKind::Other)?;
+    let mut request = vec![0x05, 0x01, 0x00];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.extend_from_slice(&[0x03, host_len]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target_port.to_be_bytes());
+    stream.write_all(&request).await.map_err(|_| FailureKind::Other)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await.map_err(|_| FailureKind::Rejected)?;
+    if reply[1] != 0x00 {
+        return Err(FailureKind::Rejected);
+    }
+    let address_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => usize::from(stream.read_u8().await.map_err(|_| FailureKind::Rejected)?),
+        _ => return Err(FailureKind::Rejected),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await.map_err(|_| FailureKind::Rejected)?;
+
+    Ok(())
+}
+
+/// In-process SOCKS5 front used with `local_dns`. reqwest hands it host names,
+/// it resolves each host once and asks ciadpi to connect to that IP, so the
+/// address a check actually went to is known.
+struct LocalDnsProxy {
+    address: std::net::SocketAddr,
+    resolved: Arc<std::sync::Mutex<HashMap<String, std::net::IpAddr>>>,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl LocalDnsProxy {
+    async fn start(upstream: std::net::SocketAddr) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind((upstream.ip(), 0)).await?;
+        let address = listener.local_addr()?;
+        let resolved = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let accept_resolved = resolved.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let resolved = accept_resolved.clone();
+                tokio::spawn(async move {
+                    let _ = serve_local_dns_connection(client, upstream, &resolved).await;
+                });
+            }
+        });
+        Ok(Self {
+            address,
+            resolved,
+            accept_task,
+        })
+    }
+
+    fn resolved_ip(&self, host: &str) -> Option<std::net::IpAddr> {
+        self.resolved.lock().ok()?.get(host).copied()
+    }
+}
+
+impl Drop for LocalDnsProxy {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+async fn serve_local_dns_connection(
+    mut client: tokio::net::TcpStream,
+    upstream: std::net::SocketAddr,
+    resolved: &std::sync::Mutex<HashMap<String, std::net::IpAddr>>,
+) -> Result<(), FailureKind> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let io = |_| FailureKind::Other;
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting).await.map_err(io)?;
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    client.read_exact(&mut methods).await.map_err(io)?;
+
+    // Greet ciadpi first so that an auth demand reaches reqwest as a handshake error.
+    let mut stream = match socks5_greet(upstream).await {
+        Ok(stream) => stream,
+        Err(kind) => {
+            let method = if kind == FailureKind::ProxyAuth { 0xff } else { 0x00 };
+            client.write_all(&[0x05, method]).await.map_err(io)?;
+            return Err(kind);
+        }
+    };
+    client.write_all(&[0x05, 0x00]).await.map_err(io)?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await.map_err(io)?;
+    let host = match request[3] {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await.map_err(io)?;
+            std::net::Ipv4Addr::from(octets).to_string()
+        }
+        0x04 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await.map_err(io)?;
+            std::net::Ipv6Addr::from(octets).to_string()
+        }
+        _ => {
+            let mut name = vec![0u8; usize::from(client.read_u8().await.map_err(io)?)];
+            client.read_exact(&mut name).await.map_err(io)?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+    };
+    let port = client.read_u16().await.map_err(io)?;
+
+    let cached = resolved.lock().ok().and_then(|hosts| hosts.get(&host).copied());
+    let ip = match cached.or(host.parse().ok()) {
+        Some(ip) => Some(ip),
+        None => resolve_ip(&host, port).await,
+    };
+    let connected = match ip {
+        Some(ip) => socks5_request(&mut stream, &ip.to_string(), port).await.map(|()| ip),
+        None => Err(FailureKind::Connect),
+    };
+    let ip = match connected {
+        Ok(ip) => ip,
+        Err(kind) => {
+            // 0x04: host unreachable, 0x05: connection refused.
+            let code = if ip.is_none() { 0x04 } else { 0x05 };
+            client.write_all(&[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.map_err(io)?;
+            return Err(kind);
+        }
+    };
+    if let Ok(mut hosts) = resolved.lock() {
+        hosts.entry(host).or_insert(ip);
+    }
+
+    client.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.map_err(io)?;
+    tokio::io::copy_bidirectional(&mut client, &mut stream).await.map_err(io)?;
+    Ok(())
+}
+
+fn create_http_client(
+    proxy_port: Option<u16>,
+    settings: &Settings,
+) -> Result<reqwest::Client, Box<dyn std::error::Error + Send + Sync>> {
+    let proxy = proxy_port.map(|port| {
+        let proxy = settings.proxy_address_family.proxy_address(port);
+        let scheme = if settings.local_dns { "socks5" } else { "socks5h" };
+        format!("{}://{}", scheme, proxy)
+    });
+    build_http_client(proxy.as_deref(), settings)
+}
+
+/// `check_domain` builds one client per domain check, so connections are never
+/// shared between domains. `disable_keepalive` only matters within a client: the
+/// spot check, HTTPS retries and the HTTP fallback of one check, and redirects.
+fn build_http_client(
+    proxy: Option<&str>,
+    settings: &Settings,
+) -> Result<reqwest::Client, Box<dyn std::error::Error + Send + Sync>> {
+    let socket = SocketOptions::from_settings(settings);
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(settings.request_timeout_sec))
+        .tcp_nodelay(socket.tcp_nodelay)
+        .tcp_keepalive(socket.tcp_keepalive_sec.map(Duration::from_secs));
+    if settings.disable_keepalive {
+        builder = builder
+            .pool_idle_timeout(Duration::ZERO)
+            .pool_max_idle_per_host(0);
+    }
+    let builder = match proxy {
+        Some(proxy) => builder.proxy(reqwest::Proxy::all(proxy)?),
+        None => builder.no_proxy(),
+    };
+
+    builder.build().map_err(Into::into)
+}
+
+async fn run_direct_baseline(domains: &[DomainSpec], settings: &Settings) -> HashSet<String> {
+    print_status("[~]", "Проверяем доступность доменов напрямую, без прокси...");
+
+    let limiter = Semaphore::new(
+        settings
+            .max_concurrent_requests
+            .unwrap_or(domains.len())
+            .clamp(1, Semaphore::MAX_PERMITS),
+    );
+    let checks = domains.iter().map(|domain| async {
+        let _permit = limiter.acquire().await;
+        (domain.name.clone(), check_domain(domain, None, settings).await.is_ok())
+    });
+
+    let direct_failed: HashSet<String> = futures::future::join_all(checks)
+        .await
+        .into_iter()
+        .filter(|(_, reachable)| !reachable)
+        .map(|(name, _)| name)
+        .collect();
+
+    print_status(
+        "[+]",
+        &format!(
+            "Напрямую доступно {} из {} доменов",
+            domains.len() - direct_failed.len(),
+            domains.len()
+        ),
+    );
+    console!();
+
+    direct_failed
+}
+
+async fn try_https(
+    client: &reqwest::Client,
+    proxy: Option<std::net::SocketAddr>,
+    domain: &DomainSpec,
+    settings: &Settings,
+) -> Result<HttpsCheck, FailureKind> {
+    if settings.tls_fingerprint != TlsFingerprint::Default {
+        return try_browser_https(proxy, domain, settings).await;
+    }
+
+    let resp = domain
+        .request(client, Scheme::Https)
+        .send()
+        .await
+        .map_err(|e| FailureKind::from_error(&e))?;
+
+    if is_accepted_response(&resp, domain, settings) {
+        let h3_port = advertised_h3_port(resp.headers());
+        accepted_body_size(resp, &settings.rules).await.map(|bytes| (bytes, h3_port))
+    } else {
+        Err(FailureKind::Rejected)
+    }
+}
+
+async fn try_http(client: &reqwest::Client, domain: &DomainSpec, settings: &Settings) -> Result<u64, FailureKind> {
+    let resp = domain
+        .request(client, Scheme::Http)
+        .send()
+        .await
+        .map_err(|e| FailureKind::from_error(&e))?;
+
+    if is_accepted_response(&resp, domain, settings) {
+        accepted_body_size(resp, &settings.rules).await
+    } else {
+        Err(FailureKind::Rejected)
+    }
+}
+
+#[cfg(not(feature = "browser-tls"))]
+async fn try_browser_https(
+    _proxy: Option<std::net::SocketAddr>,
+    _domain: &DomainSpec,
+    _settings: &Settings,
+) -> Result<HttpsCheck, FailureKind> {
+    // validate_tls_fingerprint rejects a browser fingerprint in this build.
+    Err(FailureKind::Other)
+}
+
+/// HTTPS request over an OpenSSL connection whose ClientHello follows the
+/// chosen browser: cipher suites, groups, signature algorithms, ALPN and OCSP
+/// stapling. GREASE and extension order can't be set through OpenSSL, so the
+/// JA3 hash comes close to the browser's but isn't equal to it.
+#[cfg(feature = "browser-tls")]
+async fn try_browser_https(
+    proxy: Option<std::net::SocketAddr>,
+    domain: &DomainSpec,
+    settings: &Settings,
+) -> Result<HttpsCheck, FailureKind> {
+    let timeout = Duration::from_secs(settings.request_timeout_sec);
+    time::timeout(timeout, browser_https_request(proxy, domain, settings))
+        .await
+        .unwrap_or(Err(FailureKind::Timeout))
+}
+
+#[cfg(feature = "browser-tls")]
+const BROWSER_MAX_REDIRECTS: usize = 10;
+
+#[cfg(feature = "browser-tls")]
+type BrowserResponse = (hyper::StatusCode, hyper::HeaderMap, hyper::body::Bytes);
+
+/// Follows redirects the way reqwest does: 307 and 308 keep the method and
+/// body, other redirects continue with a plain GET.
+#[cfg(feature = "browser-tls")]
+async fn browser_https_request(
+    proxy: Option<std::net::SocketAddr>,
+    domain: &DomainSpec,
+    settings: &Settings,
+) -> Result<HttpsCheck, FailureKind> {
+    use hyper::header;
+
+    let profile = BrowserTlsProfile::of(settings.tls_fingerprint);
+    let connector = profile.connector().map_err(|_| FailureKind::Other)?;
+    let mut url = reqwest::Url::parse(&domain.url(Scheme::Https)).map_err(|_| FailureKind::Other)?;
+    let mut method = domain.method.clone();
+    let mut body = domain.body.clone();
+    let mut host = domain.sni.as_ref().map(|_| domain.authority());
+
+    for _ in 0..=BROWSER_MAX_REDIRECTS {
+        let mut request = hyper::Request::builder()
+            .method(method.clone())
+            .uri(url.as_str())
+            .header(header::USER_AGENT, profile.user_agent)
+            .header(header::ACCEPT, "*/*");
+        if let Some(host) = &host {
+            request = request.header(header::HOST, host);
+        }
+        let request = match &body {
+            Some(body) => request
+                .header(header::CONTENT_TYPE, &domain.content_type)
+                .body(hyper::Body::from(body.clone())),
+            None => request.body(hyper::Body::empty()),
+        }
+        .map_err(|_| FailureKind::Other)?;
+
+        let (status, headers, bytes) = browser_send(&connector, proxy, request, settings).await?;
+        let location = headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| status.is_redirection());
+        if let Some(location) = location {
+            url = url.join(location).map_err(|_| FailureKind::Other)?;
+            if !matches!(status.as_u16(), 307 | 308) {
+                method = reqwest::Method::GET;
+                body = None;
+            }
+            host = None;
+            continue;
+        }
+
+        if !(is_expected_status(status, domain, settings) && settings.rules.accepts_headers(&headers)) {
+            return Err(FailureKind::Rejected);
+        }
+        return match settings.rules.forbidden_match(&bytes) {
+            Some(_) => Err(FailureKind::Rejected),
+            None => Ok((bytes.len() as u64, advertised_h3_port(&headers))),
+        };
+    }
+    Err(FailureKind::Other)
+}
+
+/// Sends one request over a new connection: TLS with the browser profile for
+/// `https` URLs (a redirect may lead to plain `http`), HTTP/2 when ALPN picks it.
+#[cfg(feature = "browser-tls")]
+async fn browser_send(
+    connector: &openssl::ssl::SslConnector,
+    proxy: Option<std::net::SocketAddr>,
+    request: hyper::Request<hyper::Body>,
+    settings: &Settings,
+) -> Result<BrowserResponse, FailureKind> {
+    let uri = request.uri();
+    let host = uri
+        .host()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']').to_string())
+        .ok_or(FailureKind::Other)?;
+    let https = uri.scheme_str() == Some("https");
+    let default_port = if https { Scheme::Https } else { Scheme::Http }.default_port();
+    let port = uri.port_u16().unwrap_or(default_port);
+
+    let stream = match proxy {
+        Some(proxy) => socks5_connect(proxy, &host, port).await?,
+        None => tokio::net::TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|_| FailureKind::Connect)?,
+    };
+    let _ = stream.set_nodelay(settings.tcp_nodelay);
+    if !https {
+        return hyper_exchange(stream, false, request).await;
+    }
+
+    let mut config = connector.configure().map_err(|_| FailureKind::Other)?;
+    config
+        .set_status_type(openssl::ssl::StatusType::OCSP)
+        .map_err(|_| FailureKind::Other)?;
+    let ssl = config.into_ssl(&host).map_err(|_| FailureKind::Other)?;
+    let mut stream = tokio_openssl::SslStream::new(ssl, stream).map_err(|_| FailureKind::Other)?;
+    std::pin::Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|_| FailureKind::Connect)?;
+    let http2 = stream.ssl().selected_alpn_protocol() == Some(b"h2");
+    hyper_exchange(stream, http2, request).await
+}
+
+#[cfg(feature = "browser-tls")]
+async fn hyper_exchange<S>(
+    stream: S,
+    http2: bool,
+    mut request: hyper::Request<hyper::Body>,
+) -> Result<BrowserResponse, FailureKind>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use hyper::header::HOST;
+
+    // HTTP/2 carries the host in :authority and HTTP/1.1 wants an origin-form target.
+    let mut parts = request.uri().clone().into_parts();
+    if http2 {
+        if let Some(host) = request.headers_mut().remove(HOST) {
+            parts.authority = Some(host.as_bytes().try_into().map_err(|_| FailureKind::Other)?);
+        }
+    } else {
+        if let Some(authority) = parts.authority.take() {
+            let value = authority.as_str().parse().map_err(|_| FailureKind::Other)?;
+            request.headers_mut().entry(HOST).or_insert(value);
+        }
+        parts.scheme = None;
+    }
+    *request.uri_mut() = hyper::Uri::from_parts(parts).map_err(|_| FailureKind::Other)?;
+
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(http2)
+        .handshake(stream)
+        .await
+        .map_err(|_| FailureKind::Connect)?;
+    let connection = tokio::spawn(connection);
+    let result = async {
+        let response = sender.send_request(request).await.map_err(|_| FailureKind::Other)?;
+        let (head, body) = response.into_parts();
+        let bytes = hyper::body::to_bytes(body).await.map_err(|_| FailureKind::Other)?;
+        Ok((head.status, head.headers, bytes))
+    }
+    .await;
+    connection.abort();
+    result
+}
+
+/// OpenSSL's `SSL_OP_NO_ENCRYPT_THEN_MAC`, which the openssl crate has no
+/// constant for: browsers don't send the encrypt_then_mac extension.
+#[cfg(feature = "browser-tls")]
+const SSL_OP_NO_ENCRYPT_THEN_MAC: openssl::ssl::SslOptions = openssl::ssl::SslOptions::from_bits_retain(1 << 19);
+
+/// ClientHello parameters of a browser, in the browser's preference order.
+#[cfg(feature = "browser-tls")]
+struct BrowserTlsProfile {
+    ciphersuites: &'static str,
+    cipher_list: &'static str,
+    groups: &'static str,
+    sigalgs: &'static str,
+    user_agent: &'static str,
+}
+
+#[cfg(feature = "browser-tls")]
+impl BrowserTlsProfile {
+    fn of(fingerprint: TlsFingerprint) -> Self {
+        match fingerprint {
+            TlsFingerprint::Firefox => Self {
+                ciphersuites: "TLS_AES_128_GCM_SHA256:TLS_CHACHA20_POLY1305_SHA256:TLS_AES_256_GCM_SHA384",
+                cipher_list: "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                    ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                    ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                    ECDHE-ECDSA-AES256-SHA:ECDHE-ECDSA-AES128-SHA:ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+                    AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+                groups: "X25519:P-256:P-384:P-521:ffdhe2048:ffdhe3072",
+                sigalgs: "ecdsa_secp256r1_sha256:ecdsa_secp384r1_sha384:ecdsa_secp521r1_sha512:\
+                    rsa_pss_rsae_sha256:rsa_pss_rsae_sha384:rsa_pss_rsae_sha512:\
+                    rsa_pkcs1_sha256:rsa_pkcs1_sha384:rsa_pkcs1_sha512",
+                user_agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0",
+            },
+            // `default` never reaches this path; Chrome is the fallback profile.
+            TlsFingerprint::Chrome | TlsFingerprint::Default => Self {
+                ciphersuites: "TLS_AES_128_GCM_SHA256:TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256",
+                cipher_list: "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+                    ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+                    ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+                    ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+                    AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+                groups: "X25519:P-256:P-384",
+                sigalgs: "ecdsa_secp256r1_sha256:rsa_pss_rsae_sha256:rsa_pkcs1_sha256:\
+                    ecdsa_secp384r1_sha384:rsa_pss_rsae_sha384:rsa_pkcs1_sha384:\
+                    rsa_pss_rsae_sha512:rsa_pkcs1_sha512",
+                user_agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+                    (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36",
+            },
+        }
+    }
+
+    fn connector(&self) -> Result<openssl::ssl::SslConnector, openssl::error::ErrorStack> {
+        use openssl::ssl::{SslConnector, SslMethod, SslVersion};
+
+        let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        builder.set_options(SSL_OP_NO_ENCRYPT_THEN_MAC);
+        builder.set_ciphersuites(self.ciphersuites)?;
+        builder.set_cipher_list(self.cipher_list)?;
+        builder.set_groups_list(self.groups)?;
+        builder.set_sigalgs_list(self.sigalgs)?;
+        builder.set_alpn_protos(b"\x02h2\x08http/1.1")?;
+        Ok(builder.build())
+    }
+}
+
+async fn accepted_body_size(resp: reqwest::Response, rules: &SuccessRules) -> Result<u64, FailureKind> {
+    if rules.forbidden_body.is_empty() {
+        return Ok(response_size(resp).await);
+    }
+
+    let body = resp.bytes().await.map_err(|e| FailureKind::from_error(&e))?;
+    match rules.forbidden_match(&body) {
+        Some(_) => Err(FailureKind::Rejected),
+        None => Ok(body.len() as u64),
+    }
+}
+
+async fn response_size(resp: reqwest::Response) -> u64 {
+    match resp.content_length() {
+        Some(length) => length,
+        None => resp.bytes().await.map(|body| body.len() as u64).unwrap_or(0),
+    }
+}
+
+fn is_accepted_response(resp: &reqwest::Response, domain: &DomainSpec, settings: &Settings) -> bool {
+    is_expected_status(resp.status(), domain, settings) && settings.rules.accepts_headers(resp.headers())
+}
+
+fn has_required_header(headers: &reqwest::header::HeaderMap, required: &str) -> bool {
+    match required.split_once('=') {
+        Some((name, expected)) => headers
+            .get_all(name.trim())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.trim().eq_ignore_ascii_case(expected.trim())),
+        None => headers.contains_key(required.trim()),
+    }
+}
+
+fn is_expected_status(status: reqwest::StatusCode, domain: &DomainSpec, settings: &Settings) -> bool {
+    match domain.expect_status {
+        Some(expected) => status.as_u16() == expected,
+        None => settings.rules.accepts_status(status),
+    }
+}
+
+async fn save_intermediate_results(
+    results: &Arc<Mutex<ResultStore>>,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let locked = results.lock().await;
+    if let Some(path) = &locked.stream_path {
+        print_status("[+]", &format!("Результаты дописаны в {}", path.display()));
+        return Ok(());
+    }
+
+    let summary = ReportSummary::collect(&locked)?;
+    write_results_file(&locked, &summary, settings)?;
+    print_status("[+]", &format!("Результаты сохранены в {}", settings.results_file));
+    Ok(())
+}
+
+async fn finalize_results(
+    results: Arc<Mutex<ResultStore>>,
+    summary: ReportSummary,
+    settings: &Settings,
+    group_stats: &[GroupStats],
+    session_dir: Option<&Path>,
+) -> Result<RunRecord, Box<dyn std::error::Error + Send + Sync>> {
+    let locked = results.lock().await;
+    write_results_file(&locked, &summary, settings)?;
+    
+    let total_stats = calculate_total_stats(group_stats);
+    if let Some(dir) = session_dir {
+        write_session_summary(dir, &summary, &total_stats, group_stats, settings)?;
+    }
+
+    if let Some(db_path) = &settings.results_sqlite {
+        export_results_sqlite(&locked, db_path, session_dir)?;
+        print_status("[+]", &format!("Результаты добавлены в базу {}", db_path));
+    }
+    if let Some(html_path) = &settings.results_html_file {
+        write_results_html(&locked, &summary, html_path)?;
+        print_status("[+]", &format!("HTML отчёт сохранён в {}", html_path));
+    }
+    if let Some(matrix_path) = &settings.matrix_file {
+        write_results_matrix(&locked, matrix_path)?;
+        print_status("[+]", &format!("Матрица домены × конфиги сохранена в {}", matrix_path));
+    }
+    show_final_results(&total_stats, &summary, session_dir, &settings.results_file);
+    show_recommended(&summary, settings);
+    
+    Ok(RunRecord::new(&summary, &total_stats, &settings.results_file))
+}
+
+/// Outcome of one full run, kept between `--watch` iterations and appended
+/// to the watch history.
+#[derive(Debug, Serialize)]
+struct RunRecord {
+    finished_at: String,
+    results_file: String,
+    successful: usize,
+    total: usize,
+    success_rate: f32,
+    configs_perfect: usize,
+    best_success_rate: f32,
+    config_rates: std::collections::BTreeMap<String, f32>,
+}
+
+impl RunRecord {
+    fn new(summary: &ReportSummary, stats: &GroupStats, results_file: &str) -> Self {
+        Self {
+            finished_at: Local::now().to_rfc3339(),
+            results_file: results_file.to_string(),
+            successful: stats.successful,
+            total: stats.total,
+            success_rate: stats.success_rate(),
+            configs_perfect: summary.perfect_configs(),
+            best_success_rate: summary.best_success_rate(),
+            config_rates: summary
+                .ranks
+                .iter()
+                .map(|rank| (rank.config.clone(), rank.success_rate))
+                .collect(),
+        }
+    }
+}
+
+fn show_watch_delta(previous: &RunRecord, current: &RunRecord) {
+    let dropped: Vec<(&String, f32, f32)> = current
+        .config_rates
+        .iter()
+        .filter_map(|(config, &rate)| {
+            let before = *previous.config_rates.get(config)?;
+            (rate < before).then_some((config, before, rate))
+        })
+        .collect();
+    let improved = current
+        .config_rates
+        .iter()
+        .filter(|(config, &rate)| previous.config_rates.get(*config).is_some_and(|&before| rate > before))
+        .count();
+
+    print_section("ИЗМЕНЕНИЯ С ПРОШЛОГО ПРОГОНА");
+    print_table(&[
+        (
+            "Общий успех:",
+            &format!(
+                "{:.1}% → {:.1}% ({:+.1})",
+                previous.success_rate,
+                current.success_rate,
+                current.success_rate - previous.success_rate
+            ),
+        ),
+        (
+            "Конфигов со 100%:",
+            &format!("{} → {}", previous.configs_perfect, current.configs_perfect),
+        ),
+        ("Конфигов стало лучше:", &improved.to_string()),
+        ("Конфигов стало хуже:", &dropped.len().to_string()),
+    ]);
+    for (config, before, rate) in dropped {
+        print_status(
+            "[WARN]",
+            &format!("{}: {:.1}% → {:.1}%", extract_config_name(config), before, rate),
+        );
+    }
+    console!();
+}
+
+/// Watch history entry for a run that ended with an error; deltas keep
+/// comparing against the last run that finished.
+#[derive(Debug, Serialize)]
+struct FailedRun {
+    finished_at: String,
+    error: String,
+}
+
+impl FailedRun {
+    fn new(error: String) -> Self {
+        Self {
+            finished_at: Local::now().to_rfc3339(),
+            error,
+        }
+    }
+}
+
+fn append_run_history(path: &str, record: &impl Serialize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// Starts `post_run_hook` through the shell with the run outcome in `BDPI_*`
+/// environment variables, without waiting for it, so a slow hook doesn't delay
+/// the next `--watch` run. A failing hook only produces a warning.
+fn run_post_hook(
+    settings: &Settings,
+    record: &RunRecord,
+    previous: Option<&RunRecord>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let hook = settings.post_run_hook.as_ref()?;
+
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let previous_rate = previous.map(|p| format!("{:.1}", p.success_rate)).unwrap_or_default();
+    let delta = previous
+        .map(|p| format!("{:.1}", record.success_rate - p.success_rate))
+        .unwrap_or_default();
+    let mut command = tokio::process::Command::new(shell);
+    command
+        .args([flag, hook])
+        .env("BDPI_SUCCESS_RATE", format!("{:.1}", record.success_rate))
+        .env("BDPI_PREVIOUS_SUCCESS_RATE", previous_rate)
+        .env("BDPI_RATE_DELTA", delta)
+        .env("BDPI_CONFIGS_PERFECT", record.configs_perfect.to_string())
+        .env("BDPI_RESULTS_FILE", &record.results_file);
+
+    Some(tokio::spawn(async move {
+        match command.status().await {
+            Ok(status) if status.success() => {}
+            Ok(status) => print_status("[WARN]", &format!("post_run_hook завершился с ошибкой: {}", status)),
+            Err(e) => print_status("[WARN]", &format!("Не удалось запустить post_run_hook: {}", e)),
+        }
+    }))
+}
+
+fn write_session_summary(
+    session_dir: &Path,
+    report: &ReportSummary,
+    stats: &GroupStats,
+    group_stats: &[GroupStats],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let groups = group_stats
+        .iter()
+        .enumerate()
+        .map(|(i, group)| GroupTiming {
+            group: i + 1,
+            started_sec: group.started_sec,
+            finished_sec: group.finished_sec,
+        })
+        .collect();
+    let summary = SessionSummary {
+        configs: report.ranks.len(),
+        successful: stats.successful,
+        total: stats.total,
+        bytes_received: stats.bytes_received,
+        configs_perfect: report.perfect_configs(),
+        best_success_rate: report.best_success_rate(),
+        socket_options: SocketOptions::from_settings(settings),
+        tls_fingerprint: settings.tls_fingerprint,
+        groups,
+    };
+    let content = toml::to_string(&summary)?;
+    std::fs::write(session_dir.join("summary.toml"), content)?;
+    Ok(())
+}
+
+/// Writes a domains × configs pass/fail matrix: CSV when `path` ends in
+/// `.csv`, an aligned text table otherwise.
+fn write_results_matrix(results: &ResultStore, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut configs: Vec<String> = Vec::new();
+    let mut domains: Vec<String> = Vec::new();
+    let mut cells: HashMap<String, HashMap<usize, bool>> = HashMap::new();
+
+    results.visit(|result| {
+        let column = configs.len();
+        configs.push(result.config.clone());
+        let outcomes = result
+            .successful_domains
+            .iter()
+            .map(|domain| (domain, true))
+            .chain(result.failed_domains.iter().map(|domain| (domain, false)));
+        for (domain, passed) in outcomes {
+            let row = cells.entry(domain.clone()).or_insert_with(|| {
+                domains.push(domain.clone());
+                HashMap::new()
+            });
+            row.insert(column, passed);
+        }
+        Ok(())
+    })?;
+
+    let mut file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    let passed_count = |row: &HashMap<usize, bool>| {
+        format!("{}/{}", row.values().filter(|&&passed| passed).count(), row.len())
+    };
+
+    if path.to_lowercase().ends_with(".csv") {
+        let header: Vec<String> = configs.iter().map(|config| csv_field(config)).collect();
+        writeln!(file, "domain,{},passed", header.join(","))?;
+        for domain in &domains {
+            let row = &cells[domain];
+            let values: Vec<&str> = (0..configs.len())
+                .map(|column| match row.get(&column) {
+                    Some(true) => "pass",
+                    Some(false) => "fail",
+                    None => "",
+                })
+                .collect();
+            writeln!(file, "{},{},{}", csv_field(domain), values.join(","), passed_count(row))?;
+        }
+        return Ok(());
+    }
+
+    let labels: Vec<String> = (1..=configs.len()).map(|i| format!("#{}", i)).collect();
+    let domain_width = domains.iter().map(|d| d.chars().count()).max().unwrap_or(0).max("Domain".len());
+
+    writeln!(file, "Configs:")?;
+    for (label, config) in labels.iter().zip(&configs) {
+        writeln!(file, "  {:>4} = {}", label, config)?;
+    }
+    writeln!(file)?;
+
+    let mut header = format!("{:<width$}", "Domain", width = domain_width);
+    for label in &labels {
+        header.push_str(&format!(" {:>4}", label));
+    }
+    writeln!(file, "{}  Passed", header)?;
+    writeln!(file, "{}", "─".repeat(header.chars().count() + 8))?;
+    for domain in &domains {
+        let row = &cells[domain];
+        let mut line = format!("{:<width$}", domain, width = domain_width);
+        for column in 0..configs.len() {
+            let cell = match row.get(&column) {
+                Some(true) => "✓",
+                Some(false) => "✗",
+                None => "·",
+            };
+            line.push_str(&format!(" {:>4}", cell));
+        }
+        writeln!(file, "{}  {}", line, passed_count(row))?;
+    }
+
+    Ok(())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const HTML_REPORT_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>bdpi_tester results</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+th { background: #eee; cursor: pointer; user-select: none; }
+td.num { text-align: right; white-space: nowrap; }
+code { word-break: break-all; }
+.ok { color: #17692f; }
+.fail { color: #a11; }
+</style>
+<script>
+function sortTable(col) {
+  var table = document.getElementById("configs");
+  var rows = Array.prototype.slice.call(table.tBodies[0].rows);
+  var asc = table.dataset.col == col && table.dataset.dir != "asc";
+  rows.sort(function (a, b) {
+    var x = a.cells[col].dataset.value, y = b.cells[col].dataset.value;
+    var cmp = isNaN(x) || isNaN(y) ? x.localeCompare(y) : x - y;
+    return asc ? cmp : -cmp;
+  });
+  rows.forEach(function (row) { table.tBodies[0].appendChild(row); });
+  table.dataset.col = col;
+  table.dataset.dir = asc ? "asc" : "desc";
+}
+</script>
+</head>
+<body>
+"#;
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_results_html(
+    results: &ResultStore,
+    summary: &ReportSummary,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+    let (reachable, tested) = summary.coverage();
+
+    write!(file, "{}", HTML_REPORT_HEAD)?;
+    writeln!(file, "<h1>bdpi_tester results</h1>")?;
+    writeln!(file, "<p>Generated: {}<br>", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+    writeln!(file, "Configs tested: {}<br>", summary.ranks.len())?;
+    writeln!(file, "Configs at 100%: {}<br>", summary.perfect_configs())?;
+    writeln!(file, "Union coverage: {}/{} domains</p>", reachable, tested)?;
+
+    writeln!(file, "<table id=\"configs\">")?;
+    writeln!(
+        file,
+        "<thead><tr><th onclick=\"sortTable(0)\">Config</th><th onclick=\"sortTable(1)\">Success rate</th>\
+         <th onclick=\"sortTable(2)\">Successful</th><th onclick=\"sortTable(3)\">Failed</th>\
+         <th onclick=\"sortTable(4)\">Median latency, ms</th><th>Domains</th></tr></thead>"
+    )?;
+    writeln!(file, "<tbody>")?;
+
+    results.visit(|result| {
+        let rank = result.rank();
+        let config = html_escape(&result.config);
+        let latency = rank.median_latency_ms.map_or(String::new(), |ms| ms.to_string());
+        writeln!(file, "<tr>")?;
+        writeln!(file, "<td data-value=\"{}\"><code>{}</code></td>", config, config)?;
+        writeln!(
+            file,
+            "<td class=\"num\" data-value=\"{:.1}\">{:.1}%</td>",
+            result.success_rate, result.success_rate
+        )?;
+        writeln!(file, "<td class=\"num\" data-value=\"{0}\">{0}</td>", rank.successful)?;
+        writeln!(file, "<td class=\"num\" data-value=\"{0}\">{0}</td>", rank.failed)?;
+        writeln!(
+            file,
+            "<td class=\"num\" data-value=\"{}\">{}</td>",
+            rank.median_latency_ms.unwrap_or(u64::MAX),
+            latency
+        )?;
+        writeln!(file, "<td><details><summary>{} domains</summary>", rank.total())?;
+        for domain in &result.successful_domains {
+            writeln!(file, "<div class=\"ok\">✓ {}</div>", html_escape(domain))?;
+        }
+        for domain in &result.failed_domains {
+            let reason = result
+                .failure_reasons
+                .get(domain)
+                .map_or("", |kind| kind.label());
+            writeln!(file, "<div class=\"fail\">✗ {} ({})</div>", html_escape(domain), reason)?;
+        }
+        writeln!(file, "</details></td>")?;
+        writeln!(file, "</tr>")?;
+        Ok(())
+    })?;
+
+    writeln!(file, "</tbody>")?;
+    writeln!(file, "</table>")?;
+    writeln!(file, "</body>")?;
+    writeln!(file, "</html>")?;
+    file.flush()?;
+    Ok(())
+}
+
+fn export_results_sqlite(
+    results: &ResultStore,
+    db_path: &str,
+    session_dir: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut conn = rusqlite::Connection::open(db_path)
+        .map_err(|e| format!("Failed to open {}: {}", db_path, e))?;
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY,
+            started_at TEXT NOT NULL,
+            session_dir TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS configs (
+            id INTEGER PRIMARY KEY,
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            run_at TEXT NOT NULL,
+            config TEXT NOT NULL,
+            port INTEGER NOT NULL,
+            successful INTEGER NOT NULL,
+            total INTEGER NOT NULL,
+            success_rate REAL NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS domains (
+            id INTEGER PRIMARY KEY,
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            config_id INTEGER NOT NULL REFERENCES configs(id),
+            run_at TEXT NOT NULL,
+            domain TEXT NOT NULL,
+            status TEXT NOT NULL
+        );",
+    )?;
+
+    let run_at = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "INSERT INTO runs (started_at, session_dir) VALUES (?1, ?2)",
+        rusqlite::params![run_at, session_dir.map(|d| d.display().to_string()).unwrap_or_default()],
+    )?;
+    let run_id = tx.last_insert_rowid();
+
+    results.visit(|result| {
+        let total = result.successful_domains.len() + result.failed_domains.len();
+        tx.execute(
+            "INSERT INTO configs (run_id, run_at, config, port, successful, total, success_rate)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                run_id,
+                run_at,
+                result.config,
+                result.socks5_port,
+                result.successful_domains.len() as i64,
+                total as i64,
+                result.success_rate as f64,
+            ],
+        )?;
+        let config_id = tx.last_insert_rowid();
+
+        let domains = [
+            ("success", &result.successful_domains),
+            ("failed", &result.failed_domains),
+            ("excluded", &result.excluded_domains),
+        ];
+        for (status, list) in domains {
+            for domain in list {
+                tx.execute(
+                    "INSERT INTO domains (run_id, config_id, run_at, domain, status)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![run_id, config_id, run_at, domain, status],
+                )?;
+            }
+        }
+        Ok(())
+    })?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Schema of one line of `<results_file>.ndjson`, which `stream_results`
+/// writes and which holds a `TestResult` per config. `$defs.stream_json_line`
+/// describes the lines `--stream-json` prints to stdout.
+fn results_schema() -> serde_json::Value {
+    let domain_list = serde_json::json!({
+        "type": "array",
+        "items": { "type": "string" }
+    });
+    let elapsed_sec = serde_json::json!({ "type": ["number", "null"], "minimum": 0 });
+    let finished_at = serde_json::json!({ "type": ["string", "null"], "format": "date-time" });
+    let success_rate = serde_json::json!({ "type": "number", "minimum": 0, "maximum": 100 });
+    let port = serde_json::json!({ "type": "integer", "minimum": 0, "maximum": 65535 });
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "bdpi_tester result record",
+        "type": "object",
+        "required": [
+            "config",
+            "socks5_port",
+            "successful_domains",
+            "failed_domains",
+            "excluded_domains",
+            "failure_reasons",
+            "bytes_received",
+            "attempts_successful",
+            "attempts_total",
+            "latencies_ms",
+            "success_rate"
+        ],
+        "properties": {
+            "config": { "type": "string" },
+            "socks5_port": port,
+            "successful_domains": domain_list,
+            "failed_domains": domain_list,
+            "excluded_domains": domain_list,
+            "failure_reasons": {
+                "type": "object",
+                "additionalProperties": {
+                    "enum": ["timeout", "connect", "rejected", "proxy_auth", "hung", "other"]
+                }
+            },
+            "bytes_received": { "type": "integer", "minimum": 0 },
+            "attempts_successful": { "type": "integer", "minimum": 0 },
+            "attempts_total": { "type": "integer", "minimum": 0 },
+            "latencies_ms": { "type": "array", "items": { "type": "integer", "minimum": 0 } },
+            "rescued_domains": { "type": ["array", "null"], "items": { "type": "string" } },
+            "network_down": { "type": "boolean" },
+            "www_variant_domains": domain_list,
+            "http3_successful_domains": domain_list,
+            "http3_failed_domains": domain_list,
+            "persistent_successes": { "type": ["integer", "null"], "minimum": 0 },
+            "resolved_ips": { "type": "object", "additionalProperties": { "type": "string" } },
+            "ciadpi_error": { "type": ["string", "null"] },
+            "started_sec": elapsed_sec,
+            "finished_sec": elapsed_sec,
+            "finished_at": finished_at,
+            "success_rate": success_rate
+        },
+        "$defs": {
+            "stream_json_line": {
+                "type": "object",
+                "required": ["config_id", "config", "port", "successful", "total", "success_rate"],
+                "properties": {
+                    "config_id": { "type": "string" },
+                    "config": { "type": "string" },
+                    "port": port,
+                    "successful": { "type": "integer", "minimum": 0 },
+                    "total": { "type": "integer", "minimum": 0 },
+                    "success_rate": success_rate,
+                    "started_sec": elapsed_sec,
+                    "finished_sec": elapsed_sec,
+                    "finished_at": finished_at
+                }
+            }
+        }
+    })
+}
+
+fn list_sessions(log_dir: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entries = std::fs::read_dir(log_dir)
+        .map_err(|e| format!("Failed to read {}: {}", log_dir, e))?;
+
+    let mut sessions: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    sessions.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
+
+    print_section(&format!("СЕССИИ В {}", log_dir));
+    if sessions.is_empty() {
+        print_status("[~]", "Сессии не найдены");
+        return Ok(());
+    }
+
+    console!("   {:<20} │ {:>12} │ {:>10}", "Сессия", "Конфигураций", "Успех");
+    for session in &sessions {
+        let name = session
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let (configs, rate) = match read_session_summary(session) {
+            Some(summary) => {
+                let stats = GroupStats {
+                    successful: summary.successful,
+                    total: summary.total,
+                    ..Default::default()
+                };
+                (summary.configs, format!("{:.1}%", stats.success_rate()))
+            }
+            None => (count_session_logs(session), "—".to_string()),
+        };
+
+        console!("   {:<20} │ {:>12} │ {:>10}", name, configs, rate);
+    }
+    console!();
+
+    Ok(())
+}
+
+/// Deletes session directories under `log_dir` that are older than
+/// `older_than_days` and not among the `keep_last` newest ones. Only directories
+/// named like a session timestamp are considered.
+fn clean_logs(
+    log_dir: &str,
+    older_than_days: Option<u64>,
+    keep_last: Option<usize>,
+    dry_run: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let entries = std::fs::read_dir(log_dir)
+        .map_err(|e| format!("Failed to read {}: {}", log_dir, e))?;
+
+    let mut sessions: Vec<(PathBuf, chrono::NaiveDateTime)> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let started = chrono::NaiveDateTime::parse_from_str(name, "%Y-%m-%d_%H-%M-%S").ok()?;
+            Some((path, started))
+        })
+        .collect();
+    sessions.sort_by_key(|(_, started)| std::cmp::Reverse(*started));
+
+    let cutoff = older_than_days.map(|days| Local::now().naive_local() - chrono::TimeDelta::days(days as i64));
+    let stale = stale_sessions(&sessions, cutoff, keep_last);
+
+    print_section(if dry_run { "ОЧИСТКА ЛОГОВ (ПРОБНЫЙ ЗАПУСК)" } else { "ОЧИСТКА ЛОГОВ" });
+    if stale.is_empty() {
+        print_status("[~]", &format!("Нет сессий для удаления (всего сессий: {})", sessions.len()));
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for path in stale {
+        let size = dir_size(path);
+        if dry_run {
+            print_status("[~]", &format!("Будет удалена {} ({})", path.display(), format_megabytes(size)));
+        } else if let Err(e) = std::fs::remove_dir_all(path) {
+            print_status("[WARN]", &format!("Не удалось удалить {}: {}", path.display(), e));
+            continue;
+        } else {
+            print_status("[+]", &format!("Удалена {} ({})", path.display(), format_megabytes(size)));
+        }
+        removed += 1;
+        freed += size;
+    }
+
+    console!();
+    let (removed_label, freed_label) = if dry_run {
+        ("Будет удалено сессий:", "Будет освобождено:")
+    } else {
+        ("Удалено сессий:", "Освобождено:")
+    };
+    print_table(&[
+        (removed_label, &removed.to_string()),
+        (freed_label, &format_megabytes(freed)),
+        ("Останется сессий:", &(sessions.len() - removed).to_string()),
+    ]);
+
+    Ok(())
+}
+
+/// Picks the sessions to delete from `sessions`, sorted newest first: those
+/// started before `cutoff` and past the `keep_last` newest.
+fn stale_sessions(
+    sessions: &[(PathBuf, chrono::NaiveDateTime)],
+    cutoff: Option<chrono::NaiveDateTime>,
+    keep_last: Option<usize>,
+) -> Vec<&PathBuf> {
+    sessions
+        .iter()
+        .enumerate()
+        .filter(|(i, (_, started))| {
+            keep_last.is_none_or(|keep| *i >= keep) && cutoff.is_none_or(|cutoff| *started < cutoff)
+        })
+        .map(|(_, (path, _))| path)
+        .collect()
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn read_session_summary(session_dir: &Path) -> Option<SessionSummary> {
+    let content = std::fs::read_to_string(session_dir.join("summary.toml")).ok()?;
+    toml::from_str(&content).ok()
+}
+
+fn count_session_logs(session_dir: &Path) -> usize {
+    session_log_files(session_dir).len()
+}
+
+fn session_log_files(session_dir: &Path) -> Vec<PathBuf> {
+    let Ok(groups) = std::fs::read_dir(session_dir) else {
+        return Vec::new();
+    };
+
+    groups
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_dir())
+        .filter_map(|path| std::fs::read_dir(path).ok())
+        .flat_map(|files| files.filter_map(|f| f.ok()))
+        .filter(|f| f.file_name().to_string_lossy().starts_with("ciadpi_"))
+        .map(|f| f.path())
+        .collect()
+}
+
+/// Deletes the oldest ciadpi logs of the session so that at most `max_files` remain.
+fn prune_session_logs(session_dir: &Path, max_files: usize) -> usize {
+    let mut logs: Vec<(std::time::SystemTime, PathBuf)> = session_log_files(session_dir)
+        .into_iter()
+        .map(|path| {
+            let modified = std::fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .unwrap_or(std::time::UNIX_EPOCH);
+            (modified, path)
+        })
+        .collect();
+    if logs.len() <= max_files {
+        return 0;
+    }
+
+    logs.sort();
+    let excess = logs.len() - max_files;
+    logs.iter()
+        .take(excess)
+        .filter(|(_, path)| std::fs::remove_file(path).is_ok())
+        .count()
+}
+
+fn calculate_total_stats(group_stats: &[GroupStats]) -> GroupStats {
+    let successful: usize = group_stats.iter().map(|s| s.successful).sum();
+    let total: usize = group_stats.iter().map(|s| s.total).sum();
+    let bytes_received: u64 = group_stats.iter().map(|s| s.bytes_received).sum();
+    GroupStats {
+        successful,
+        total,
+        bytes_received,
+        ..Default::default()
+    }
+}
+
+fn show_welcome_message() {
+    console!();
+    print_banner("BDPI TESTER", "Инструмент тестирования прокси-конфигураций");
+    console!();
+    console!("Перед запуском убедитесь, что:");
+    console!("   ✓ Файл 'settings.toml' содержит нужные настройки");
+    console!("   ✓ Файл 'configs.txt' содержит список конфигураций");
+    console!("   ✓ Файл 'domains.txt' содержит домены для проверки");
+    console!("   ✓ Исполняемый файл ciadpi доступен в PATH");
+    console!();
+}
+
+fn display_startup_info(settings: &Settings, configs: &[String], domains: &[DomainSpec]) {
+    console!();
+    print_section("СТАТИСТИКА ЗАГРУЗКИ");
+    print_table(&[
+        ("Конфигураций загружено:", &configs.len().to_string()),
+        ("Доменов для проверки:", &domains.len().to_string()),
+    ]);
+
+    let (port_label, port_value) = if settings.port_pool.is_empty() {
+        ("Стартовый порт:", settings.start_port.to_string())
+    } else {
+        ("Пул портов:", format_ports(&settings.port_pool))
+    };
+
+    print_section("НАСТРОЙКИ");
+    print_table(&[
+        ("Размер группы:", &settings.group_size.to_string()),
+        (port_label, &port_value),
+        ("Задержка между группами:", &format!("{} мс", settings.group_delay_ms)),
+        ("Таймаут запроса:", &format!("{} сек", settings.request_timeout_sec)),
+        ("Сокеты:", &SocketOptions::from_settings(settings).describe()),
+        ("TLS отпечаток:", settings.tls_fingerprint.name()),
+        ("Папка логов:", &settings.log_dir),
+        ("Файл результатов:", &settings.results_file),
+    ]);
+}
+
+fn estimate_remaining(elapsed: Duration, completed_groups: usize, total_groups: usize) -> Option<Duration> {
+    if completed_groups == 0 {
+        return None;
+    }
+
+    let average = elapsed / completed_groups as u32;
+    Some(average * (total_groups - completed_groups) as u32)
+}
+
+fn print_group_header(
+    group_num: usize,
+    total_groups: usize,
+    ports: &[u16],
+    group_size: usize,
+    eta: Option<Duration>,
+) {
+    if ports.len() < group_size {
+        print_section(&format!(
+            "ГРУППА {}/{} (неполная: {} из {} конфигов)",
+            group_num,
+            total_groups,
+            ports.len(),
+            group_size
+        ));
+    } else {
+        print_section(&format!("ГРУППА {}/{}", group_num, total_groups));
+    }
+    console!("   Конфигураций в группе: {}", ports.len());
+    console!("   Порты: {}", format_ports(ports));
+    if let Some(eta) = eta {
+        console!("   Осталось примерно: {}", format_duration(eta));
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{} с", s),
+        (0, m, s) => format!("{} мин {} с", m, s),
+        (h, m, _) => format!("{} ч {} мин", h, m),
+    }
+}
+
+fn format_ports(ports: &[u16]) -> String {
+    let is_contiguous = ports.windows(2).all(|w| w[0].checked_add(1) == Some(w[1]));
+    match (ports.first(), ports.last()) {
+        (Some(first), Some(last)) if is_contiguous && first != last => format!("{}-{}", first, last),
+        _ => ports
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+fn print_config_start(config: &str, port: u16) {
+    let config_name = extract_config_name(config);
+    print_status("[~]", &format!("Запускаем {} на порту {}...", config_name, port));
+}
+
+fn print_config_result(config_name: &str, successful: usize, total: usize) {
+    let rate = (successful as f32 / total as f32 * 100.0) as u32;
+    let status = match rate {
+        90..=100 => "[OK]",
+        50..=89 => "[WARN]",
+        _ => "[FAIL]",
+    };
+    
+    console!("   {} {}: {}/{} успешно ({}%)", status, config_name, successful, total, rate);
+}
+
+fn print_config_error(config: &str) {
+    console!("   [FAIL] {}: завершился с ошибкой", extract_config_name(config));
+}
+
+fn print_group_summary(group_num: usize, stats: &GroupStats) {
+    let rate = stats.success_rate() as u32;
+    console!();
+    console!("   Группа {} завершена: {}/{} успешно ({}%)", 
+        group_num, stats.successful, stats.total, rate);
+
+    if !stats.process_exits.is_empty() {
+        console!();
+        console!("   Завершение процессов ciadpi:");
+        let descriptions: Vec<String> = stats.process_exits.iter().map(|(_, exit)| exit.describe()).collect();
+        let rows: Vec<(&str, &str)> = stats
+            .process_exits
+            .iter()
+            .zip(&descriptions)
+            .map(|((name, _), description)| (name.as_str(), description.as_str()))
+            .collect();
+        print_table(&rows);
+    }
+}
+
+fn show_final_results(
+    stats: &GroupStats,
+    summary: &ReportSummary,
+    session_dir: Option<&Path>,
+    results_file: &str,
+) {
+    let coverage = summary.coverage();
+    let mut perfect = format!("{} из {}", summary.perfect_configs(), summary.ranks.len());
+    if summary.perfect_configs() == 0 && !summary.ranks.is_empty() {
+        perfect.push_str(&format!(" (лучший: {:.1}%)", summary.best_success_rate()));
+    }
+
+    console!();
+    print_section("ТЕСТИРОВАНИЕ ЗАВЕРШЕНО");
+    console!();
+    
+    console!("   Общая статистика:");
+    print_table(&[
+        ("Всего тестов:", &stats.total.to_string()),
+        ("Успешных:", &stats.successful.to_string()),
+        ("Процент успеха:", &format!("{:.1}%", stats.success_rate())),
+        ("Получено данных:", &format_megabytes(stats.bytes_received)),
+        ("Покрытие доменов:", &format!("{}/{} хотя бы одним конфигом", coverage.0, coverage.1)),
+        ("Конфигов на 100%:", &perfect),
+    ]);
+    
+    console!("   Результаты сохранены:");
+    print_table(&[
+        ("Файл результатов:", results_file),
+        (
+            "Папка логов:",
+            &session_dir.map(|d| d.display().to_string()).unwrap_or_else(|| "отключены".to_string()),
+        ),
+    ]);
+}
+
+fn show_recommended(summary: &ReportSummary, settings: &Settings) {
+    let Some(recommended) = summary.recommended(settings) else {
+        return;
+    };
+
+    print_section(&format!("РЕКОМЕНДУЕМЫЕ КОНФИГИ: {}", recommended.len()));
+    if recommended.is_empty() {
+        console!("   Ни один конфиг не прошёл все пороги recommend_thresholds");
+    }
+    for rank in recommended {
+        console!("   ★ {:>5.1}%  {}", rank.success_rate, display_config(&rank.config, settings));
+    }
+    console!();
+}
+
+fn print_banner(title: &str, subtitle: &str) {
+    const WIDTH: usize = 60;
+    console!("┌{}┐", "─".repeat(WIDTH));
+    console!("│{:^WIDTH$}│", title);
+    console!("│{:^WIDTH$}│", subtitle);
+    console!("└{}┘", "─".repeat(WIDTH));
+}
+
+fn print_section(title: &str) {
+    console!("\n▶ {}\n  {}", title, "─".repeat(58));
+}
+
+fn print_table(rows: &[(&str, &str)]) {
+    let max_left = rows.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+    
+    let mut table = String::new();
+    for (left, right) in rows {
+        table.push_str(&format!("   {:width$} │ {}\n", left, right, width = max_left));
+    }
+    console!("{}", table);
+}
+
+fn print_status(prefix: &str, message: &str) {
+    console!("   {} {}", prefix, message);
+}
+
+fn wait_for_start() {
+    print_status("[?]", "Для начала работы введите 'start' и нажмите Enter:");
+    wait_for_input("start", "Пожалуйста, введите 'start' для продолжения:");
+    console!();
+}
+
+fn confirm_start() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    console!("   Нажмите Enter для начала или Ctrl+C для отмены...");
+    let mut input = String::new();
+    stdin().read_line(&mut input)?;
+    Ok(())
+}
+
+fn wait_for_quit() {
+    print_status("[?]", "Для выхода введите 'quit' и нажмите Enter:");
+    wait_for_input("quit", "Пожалуйста, введите 'quit' для выхода:");
+}
+
+fn wait_for_input(expected: &str, retry_message: &str) {
+    loop {
+        let mut input = String::new();
+        if stdin().read_line(&mut input).is_ok() && input.trim().eq_ignore_ascii_case(expected) {
+            break;
+        }
+        print_status("[ERROR]", retry_message);
+    }
+}
+
+async fn wait_between_groups(delay_ms: u64) {
+    console!();
+    print_status("[~]", &format!("Ожидание {} мс перед следующей группой...", delay_ms));
+    time::sleep(Duration::from_millis(delay_ms)).await;
+}
+
+fn sanitize_filename(config: &str, port: u16) -> String {
+    let base = config
+        .split_whitespace()
+        .next()
+        .unwrap_or("cfg")
+        .chars()
+        .take(20)
+        .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
+        .collect::<String>();
+    
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    
+    format!("{}_p{}_t{}", base, port, timestamp)
+}
+
+fn wilson_interval(successes: usize, trials: usize) -> (f32, f32) {
+    const Z: f64 = 1.96;
+
+    if trials == 0 {
+        return (0.0, 0.0);
+    }
+
+    let n = trials as f64;
+    let p = successes as f64 / n;
+    let denominator = 1.0 + Z * Z / n;
+    let center = (p + Z * Z / (2.0 * n)) / denominator;
+    let margin = Z * (p * (1.0 - p) / n + Z * Z / (4.0 * n * n)).sqrt() / denominator;
+
+    (
+        ((center - margin).max(0.0) * 100.0) as f32,
+        ((center + margin).min(1.0) * 100.0) as f32,
+    )
+}
+
+fn format_megabytes(bytes: u64) -> String {
+    format!("{:.2} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn config_id(config: &str) -> String {
+    let hash = config.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    format!("{:08x}", hash)
+}
+
+fn is_config_truncated(config: &str, settings: &Settings) -> bool {
+    settings
+        .max_config_display_len
+        .is_some_and(|max| config.chars().count() > max)
+}
+
+fn display_config(config: &str, settings: &Settings) -> String {
+    match settings.max_config_display_len {
+        Some(max) if is_config_truncated(config, settings) => {
+            let shortened: String = config.chars().take(max.saturating_sub(1)).collect();
+            format!("{}… [{}]", shortened.trim_end(), config_id(config))
+        }
+        _ => config.to_string(),
+    }
+}
+
+fn extract_config_name(config: &str) -> String {
+    config
+        .split_whitespace()
+        .next()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn write_results_file(
+    results: &ResultStore,
+    summary: &ReportSummary,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&settings.results_file)?;
+
+    write_header(&mut file, summary, settings)?;
+    if let Some(recommended) = summary.recommended(settings) {
+        write_recommended(&mut file, &recommended, settings)?;
+    }
+    if let Some(suite) = &summary.suite {
+        write_suite_summary(&mut file, suite, settings)?;
+    }
+    write_top_configs(&mut file, &summary.ranks, settings)?;
+    if settings.bottom_configs_count > 0 {
+        write_bottom_configs(&mut file, &summary.ranks, settings)?;
+    }
+    if let Some(thresholds) = settings.tier_thresholds {
+        write_tiers(&mut file, &summary.ranks, thresholds, settings)?;
+    }
+    write_tld_summary(&mut file, &summary.by_tld)?;
+    write_covering_set(&mut file, summary, settings)?;
+    write_dominated_configs(&mut file, summary, settings)?;
+    if let Some(baseline) = &settings.baseline_config {
+        write_baseline_comparison(&mut file, summary, baseline, settings)?;
+    }
+    write_detailed_results(&mut file, results, settings)?;
+    write_config_index(&mut file, &summary.ranks, settings)?;
+    
+    file.flush()?;
+    Ok(())
+}
+
+fn write_header(
+    file: &mut File,
+    summary: &ReportSummary,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (reachable, tested) = summary.coverage();
+    writeln!(file, "{}", "=".repeat(70))?;
+    writeln!(file, "  BDPI TESTER - RESULTS REPORT")?;
+    writeln!(file, "{}", "=".repeat(70))?;
+    writeln!(file)?;
+    writeln!(file, "Generated: {}", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
+    writeln!(file, "Total configs tested: {}", summary.ranks.len())?;
+    writeln!(file, "TLS fingerprint: {}", settings.tls_fingerprint.name())?;
+    writeln!(file, "Union coverage: {}/{} domains reachable by at least one config", reachable, tested)?;
+    write!(file, "Configs at 100%: {} of {}", summary.perfect_configs(), summary.ranks.len())?;
+    if summary.perfect_configs() == 0 && !summary.ranks.is_empty() {
+        write!(file, " (best: {:.1}%)", summary.best_success_rate())?;
+    }
+    writeln!(file)?;
+    writeln!(file)?;
+    Ok(())
+}
+
+fn write_top_configs(
+    file: &mut File,
+    ranks: &[ConfigRank],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  TOP 10 BEST PERFORMING CONFIGS")?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut sorted_results: Vec<&ConfigRank> = ranks.iter().collect();
+    sorted_results.sort_by(|a, b| {
+        b.success_rate
+            .partial_cmp(&a.success_rate)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| b.successful.cmp(&a.successful))
+    });
+
+    let floor = settings.top_min_success_rate;
+    let top: Vec<&ConfigRank> = sorted_results
+        .into_iter()
+        .take(10)
+        .filter(|result| floor.is_none_or(|min| result.success_rate >= min))
+        .collect();
+    if let Some(min) = floor {
+        if top.is_empty() {
+            writeln!(file, "  No config reached top_min_success_rate ({:.1}%)", min)?;
+            writeln!(file)?;
+        } else if top.len() < ranks.len().min(10) {
+            writeln!(
+                file,
+                "  Showing {} configs at or above top_min_success_rate ({:.1}%)",
+                top.len(),
+                min
+            )?;
+            writeln!(file)?;
+        }
+    }
+
+    for (rank, result) in top.iter().enumerate() {
+        let medal = match rank {
+            0 => "\u{1F947}",
+            1 => "\u{1F948}",
+            2 => "\u{1F949}",
+            _ => "  ",
+        };
+        
+        let recommended = settings
+            .recommend_thresholds
+            .is_some_and(|thresholds| result.is_recommended(thresholds));
+        writeln!(
+            file,
+            "{} #{:<2} {} (port {}){}",
+            medal,
+            rank + 1,
+            display_config(&result.config, settings),
+            result.socks5_port,
+            if recommended { " ★ RECOMMENDED" } else { "" }
+        )?;
+        write!(
+            file,
+            "       Success: {}/{} ({:.1}%)",
+            result.successful,
+            result.total(),
+            result.success_rate
+        )?;
+        write_confidence_interval(file, result.confidence, settings)?;
+        writeln!(file)?;
+        if let Some(rescued) = result.rescued {
+            writeln!(file, "       Rescued (blocked directly): {}", rescued)?;
+        }
+        writeln!(file)?;
+    }
+
+    Ok(())
+}
+
+fn write_bottom_configs(
+    file: &mut File,
+    ranks: &[ConfigRank],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let count = settings.bottom_configs_count;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  WORST {} CONFIGS", count)?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut sorted_results: Vec<&ConfigRank> = ranks.iter().collect();
+    sorted_results.sort_by(|a, b| {
+        a.success_rate
+            .partial_cmp(&b.success_rate)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.successful.cmp(&b.successful))
+    });
+
+    for (rank, result) in sorted_results.iter().take(count).enumerate() {
+        writeln!(
+            file,
+            "   #{:<2} {} (port {})",
+            rank + 1,
+            display_config(&result.config, settings),
+            result.socks5_port
+        )?;
+        writeln!(
+            file,
+            "       Success: {}/{} ({:.1}%)",
+            result.successful,
+            result.total(),
+            result.success_rate
+        )?;
+        if let Some((kind, failures)) = result.dominant_failure {
+            writeln!(
+                file,
+                "       Main failure: {} ({}/{})",
+                kind.label(),
+                failures,
+                result.failed
+            )?;
+        }
+        writeln!(file)?;
+    }
+
+    Ok(())
+}
+
+fn write_recommended(
+    file: &mut File,
+    recommended: &[&ConfigRank],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  RECOMMENDED CONFIGS ({})", recommended.len())?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    if recommended.is_empty() {
+        writeln!(file, "   No config meets all recommend_thresholds")?;
+    }
+    for rank in recommended {
+        writeln!(file, "   ★ {} (port {})", display_config(&rank.config, settings), rank.socks5_port)?;
+        writeln!(
+            file,
+            "       Success: {:.1}%  Attempts: {:.1}%  Median latency: {}",
+            rank.success_rate,
+            rank.attempt_success_rate,
+            rank.median_latency_ms.map_or("n/a".to_string(), |ms| format!("{} ms", ms))
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_suite_summary(
+    file: &mut File,
+    suite: &SuiteStats,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let quorum = settings.domain_success_quorum.and_then(|quorum| quorum.runs());
+    writeln!(file, "{}", "─".repeat(70))?;
+    match quorum {
+        Some(quorum) => writeln!(
+            file,
+            "  SUITE REPEATS ({} RUNS, RANKED BY DOMAINS REACHED IN {}+ RUNS)",
+            suite.runs(),
+            quorum
+        )?,
+        None => writeln!(file, "  SUITE REPEATS ({} RUNS, MEAN SUCCESS RATE)", suite.runs())?,
+    }
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut rows: Vec<_> = suite
+        .rates
+        .iter()
+        .map(|(config, rates)| {
+            let (mean, variance) = mean_and_variance(rates);
+            let reached = quorum.map(|quorum| suite.quorum_reached(config, quorum));
+            (config, rates, mean, variance, reached)
+        })
+        .collect();
+    let quorum_rate = |reached: Option<(usize, usize)>| {
+        reached.map_or(0.0, |(reached, tested)| reached as f32 / tested.max(1) as f32)
+    };
+    rows.sort_by(|a, b| {
+        quorum_rate(b.4)
+            .partial_cmp(&quorum_rate(a.4))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal))
+            .then_with(|| a.3.partial_cmp(&b.3).unwrap_or(std::cmp::Ordering::Equal))
+    });
+
+    for (rank, (config, rates, mean, variance, reached)) in rows.iter().enumerate() {
+        let runs: Vec<String> = rates.iter().map(|rate| format!("{:.1}", rate)).collect();
+        writeln!(file, "   #{:<2} {}", rank + 1, display_config(config, settings))?;
+        if let (Some(quorum), Some((reached, tested))) = (quorum, reached) {
+            writeln!(
+                file,
+                "       Reached in {}+ of {} runs: {}/{} domains ({:.1}%)",
+                quorum,
+                suite.runs(),
+                reached,
+                tested,
+                quorum_rate(Some((*reached, *tested))) * 100.0
+            )?;
+        }
+        writeln!(
+            file,
+            "       Mean: {:.1}%  Variance: {:.2}  Std dev: {:.1}  Runs: {}",
+            mean,
+            variance,
+            variance.sqrt(),
+            runs.join(" / ")
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_covering_set(
+    file: &mut File,
+    summary: &ReportSummary,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let picked = summary.covering_set();
+    if picked.is_empty() {
+        return Ok(());
+    }
+
+    let (reachable, _) = summary.coverage();
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  MINIMAL COVERING SET ({} CONFIGS FOR {} DOMAINS)", picked.len(), reachable)?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut covered = 0;
+    for (step, (config, new)) in picked.iter().enumerate() {
+        covered += new;
+        writeln!(
+            file,
+            "   {:>2}. +{:<4} (total {}/{})  {}",
+            step + 1,
+            new,
+            covered,
+            reachable,
+            display_config(config, settings)
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_dominated_configs(
+    file: &mut File,
+    summary: &ReportSummary,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dominated = summary.dominated();
+    if dominated.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  DOMINATED CONFIGS ({} CAN BE DROPPED)", dominated.len())?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+    writeln!(file, "  Each config reaches no domain the other doesn't and isn't faster.")?;
+    writeln!(file)?;
+
+    let latency = |rank: &ConfigRank| rank.median_latency_ms.map_or("n/a".to_string(), |ms| format!("{} ms", ms));
+    for (rank, other, domains, other_domains) in dominated {
+        writeln!(file, "   {}", display_config(&rank.config, settings))?;
+        writeln!(
+            file,
+            "      is dominated by {} ({} vs {} domains, median {} vs {})",
+            display_config(&other.config, settings),
+            domains,
+            other_domains,
+            latency(rank),
+            latency(other)
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_baseline_comparison(
+    file: &mut File,
+    summary: &ReportSummary,
+    baseline: &str,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  COMPARISON WITH BASELINE CONFIG")?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let Some(reference) = summary.ranks.iter().position(|rank| rank.config == baseline) else {
+        writeln!(file, "  Baseline {} was not tested in this run", display_config(baseline, settings))?;
+        writeln!(file)?;
+        return Ok(());
+    };
+    let (_, baseline_ok) = &summary.covered_by[reference];
+    let baseline_failed = &summary.failed_by[reference];
+    let baseline_rate = summary.ranks[reference].success_rate;
+    writeln!(file, "  Baseline: {} ({:.1}%)", display_config(baseline, settings), baseline_rate)?;
+    writeln!(file)?;
+
+    let names = |bits: DomainBits| -> Vec<String> {
+        bits.ids().map(|id| summary.domains.name(id).to_string()).collect()
+    };
+    let mut rows: Vec<(String, f32, Vec<String>, Vec<String>)> = Vec::new();
+    for ((rank, (_, reached)), failed) in summary.ranks.iter().zip(&summary.covered_by).zip(&summary.failed_by) {
+        if rank.config == baseline {
+            continue;
+        }
+        let fixed = names(reached.intersection(baseline_failed));
+        let regressed = names(failed.intersection(baseline_ok));
+        rows.push((rank.config.clone(), rank.success_rate, fixed, regressed));
+    }
+    rows.sort_by_key(|(_, _, fixed, regressed)| std::cmp::Reverse(fixed.len() as i64 - regressed.len() as i64));
+
+    for (config, rate, fixed, regressed) in &rows {
+        writeln!(
+            file,
+            "  +{:<3} -{:<3} (net {:+})  {} ({:.1}%)",
+            fixed.len(),
+            regressed.len(),
+            fixed.len() as i64 - regressed.len() as i64,
+            display_config(config, settings),
+            rate
+        )?;
+        if !fixed.is_empty() {
+            writeln!(file, "       Fixed: {}", fixed.join(", "))?;
+        }
+        if !regressed.is_empty() {
+            writeln!(file, "       Regressed: {}", regressed.join(", "))?;
+        }
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_tld_summary(
+    file: &mut File,
+    by_tld: &HashMap<String, GroupStats>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if by_tld.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  SUCCESS RATE BY TLD (ALL CONFIGS)")?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut rows: Vec<(&String, &GroupStats)> = by_tld.iter().collect();
+    rows.sort_by(|(a_tld, a), (b_tld, b)| {
+        b.success_rate()
+            .partial_cmp(&a.success_rate())
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a_tld.cmp(b_tld))
+    });
+
+    for (tld, stats) in rows {
+        writeln!(
+            file,
+            "   {:<12} {:>6}/{:<6} ({:.1}%)",
+            tld,
+            stats.successful,
+            stats.total,
+            stats.success_rate()
+        )?;
+    }
+    writeln!(file)?;
+
+    Ok(())
+}
+
+fn write_tiers(
+    file: &mut File,
+    ranks: &[ConfigRank],
+    thresholds: TierThresholds,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  CONFIG TIERS")?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut sorted_results: Vec<&ConfigRank> = ranks.iter().collect();
+    sorted_results.sort_by(|a, b| {
+        b.success_rate
+            .partial_cmp(&a.success_rate)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let tiers = [
+        (format!("EXCELLENT (>= {:.0}%)", thresholds.excellent), thresholds.excellent, f32::INFINITY),
+        (format!("GOOD (>= {:.0}%)", thresholds.good), thresholds.good, thresholds.excellent),
+        (format!("POOR (< {:.0}%)", thresholds.good), f32::NEG_INFINITY, thresholds.good),
+    ];
+
+    for (title, min, max) in tiers {
+        let members: Vec<&&ConfigRank> = sorted_results
+            .iter()
+            .filter(|r| r.success_rate >= min && r.success_rate < max)
+            .collect();
+
+        writeln!(file, "  {} - {} configs", title, members.len())?;
+        for result in members {
+            writeln!(
+                file,
+                "    {:>5.1}%  {}",
+                result.success_rate,
+                display_config(&result.config, settings)
+            )?;
+        }
+        writeln!(file)?;
+    }
+
+    Ok(())
+}
+
+fn domain_tld(domain: &str) -> String {
+    let host = domain.split(['/', ':']).next().unwrap_or(domain);
+    match host.rsplit_once('.') {
+        Some((_, tld)) => format!(".{}", tld.to_lowercase()),
+        None => host.to_lowercase(),
+    }
+}
+
+fn write_detailed_results(
+    file: &mut File,
+    results: &ResultStore,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file, "  DETAILED RESULTS FOR ALL CONFIGS")?;
+    writeln!(file, "{}", "─".repeat(70))?;
+    writeln!(file)?;
+
+    let mut index = 0;
+    results.visit(|result| {
+        index += 1;
+        write_single_result(file, index, result, results.direct_failed.as_ref(), settings)
+    })
+}
+
+fn write_latency_histogram(
     file: &mut File,
-    total_configs: usize,
+    latencies_ms: &[u64],
+    buckets_ms: &[u64],
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    writeln!(file, "{}", "=".repeat(70))?;
-    writeln!(file, "  BDPI TESTER - RESULTS REPORT")?;
-    writeln!(file, "{}", "=".repeat(70))?;
-    writeln!(file)?;
-    writeln!(file, "Generated: {}", Local::now().format("%Y-%m-%d %H:%M:%S"))?;
-    writeln!(file, "Total configs tested: {}", total_configs)?;
+    const BAR_WIDTH: usize = 30;
+
+    if latencies_ms.is_empty() || buckets_ms.is_empty() {
+        return Ok(());
+    }
+
+    let mut bounds = buckets_ms.to_vec();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut counts = vec![0usize; bounds.len() + 1];
+    for &latency in latencies_ms {
+        let bucket = bounds.partition_point(|&bound| bound <= latency);
+        counts[bucket] += 1;
+    }
+
+    let labels: Vec<String> = (0..counts.len())
+        .map(|i| match (i.checked_sub(1).map(|j| bounds[j]), bounds.get(i)) {
+            (None, Some(upper)) => format!("< {} ms", upper),
+            (Some(lower), Some(upper)) => format!("{}-{} ms", lower, upper),
+            (Some(lower), None) => format!(">= {} ms", lower),
+            (None, None) => unreachable!(),
+        })
+        .collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+
+    writeln!(file, "    Latency ({} successful requests):", latencies_ms.len())?;
+    for (label, &count) in labels.iter().zip(&counts) {
+        let bar = "█".repeat((count * BAR_WIDTH).div_ceil(max_count));
+        let row = format!("{} {}", bar, count);
+        writeln!(file, "      {:>width$} │ {}", label, row.trim_start(), width = label_width)?;
+    }
     writeln!(file)?;
+
     Ok(())
 }
 
-fn write_top_configs(
+fn write_config_index(
     file: &mut File,
-    results: &[TestResult],
+    ranks: &[ConfigRank],
+    settings: &Settings,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let truncated: Vec<&ConfigRank> = ranks
+        .iter()
+        .filter(|r| is_config_truncated(&r.config, settings))
+        .collect();
+    if truncated.is_empty() {
+        return Ok(());
+    }
+
     writeln!(file, "{}", "─".repeat(70))?;
-    writeln!(file, "  TOP 10 BEST PERFORMING CONFIGS")?;
+    writeln!(file, "  FULL CONFIGS BY ID")?;
     writeln!(file, "{}", "─".repeat(70))?;
     writeln!(file)?;
 
-    let mut sorted_results: Vec<&TestResult> = results.iter().collect();
-    sorted_results.sort_by(|a, b| {
-        b.success_rate
-            .partial_cmp(&a.success_rate)
-            .unwrap_or(std::cmp::Ordering::Equal)
-            .then_with(|| b.successful_domains.len().cmp(&a.successful_domains.len()))
-    });
-
-    for (rank, result) in sorted_results.iter().take(10).enumerate() {
-        let total = result.successful_domains.len() + result.failed_domains.len();
-        let medal = match rank {
-            0 => "\u{1F947}",
-            1 => "\u{1F948}",
-            2 => "\u{1F949}",
-            _ => "  ",
-        };
-        
-        writeln!(
-            file,
-            "{} #{:<2} {} (port {})",
-            medal,
-            rank + 1,
-            result.config,
-            result.socks5_port
-        )?;
-        writeln!(
-            file,
-            "       Success: {}/{} ({:.1}%)",
-            result.successful_domains.len(),
-            total,
-            result.success_rate
-        )?;
-        writeln!(file)?;
+    for result in truncated {
+        writeln!(file, "[{}] {}", config_id(&result.config), result.config)?;
     }
+    writeln!(file)?;
 
     Ok(())
 }
 
-fn write_detailed_results(
+fn write_confidence_interval(
     file: &mut File,
-    results: &[TestResult],
+    (low, high): (f32, f32),
+    settings: &Settings,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    writeln!(file, "{}", "─".repeat(70))?;
-    writeln!(file, "  DETAILED RESULTS FOR ALL CONFIGS")?;
-    writeln!(file, "{}", "─".repeat(70))?;
-    writeln!(file)?;
-
-    for (idx, result) in results.iter().enumerate() {
-        write_single_result(file, idx + 1, result)?;
+    if settings.repeat_count > 1 {
+        write!(file, " [95% CI: {:.1}–{:.1}%]", low, high)?;
     }
+    Ok(())
+}
 
+/// Lists domains three per line, cutting the list at `limit` with a count of the rest.
+fn write_domain_list(
+    file: &mut File,
+    domains: &[String],
+    limit: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let shown = &domains[..limit.map_or(domains.len(), |limit| limit.min(domains.len()))];
+    for (i, domain) in shown.iter().enumerate() {
+        write!(file, "      {}", domain)?;
+        if (i + 1) % 3 == 0 || i == shown.len() - 1 {
+            writeln!(file)?;
+        } else {
+            write!(file, ", ")?;
+        }
+    }
+    if shown.len() < domains.len() {
+        writeln!(file, "      ... and {} more", domains.len() - shown.len())?;
+    }
     Ok(())
 }
 
+fn failure_verdict(result: &TestResult, direct_failed: Option<&HashSet<String>>) -> Option<String> {
+    const CLUSTER_SHARE: f32 = 0.6;
+
+    let failed = result.failed_domains.len();
+    if failed < 2 {
+        return None;
+    }
+    let is_cluster = |count: usize| count as f32 / failed as f32 >= CLUSTER_SHARE;
+
+    if let Some(direct_failed) = direct_failed {
+        let also_direct = result.failed_domains.iter().filter(|d| direct_failed.contains(*d)).count();
+        if is_cluster(also_direct) {
+            return Some(format!(
+                "fails mostly on domains unreachable directly too ({}/{} failures)",
+                also_direct, failed
+            ));
+        }
+    }
+
+    let mut failed_by_tld: HashMap<String, usize> = HashMap::new();
+    let mut tested_by_tld: HashMap<String, usize> = HashMap::new();
+    for domain in &result.failed_domains {
+        *failed_by_tld.entry(domain_tld(domain)).or_default() += 1;
+    }
+    for domain in result.successful_domains.iter().chain(&result.failed_domains) {
+        *tested_by_tld.entry(domain_tld(domain)).or_default() += 1;
+    }
+
+    let (tld, count) = failed_by_tld
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))?;
+    if is_cluster(*count) && tested_by_tld.len() > 1 {
+        let tld_total = tested_by_tld.get(tld).copied().unwrap_or(*count);
+        return Some(format!(
+            "fails mostly on {} domains ({}/{} failures, {} of {} {} domains failed)",
+            tld, count, failed, count, tld_total, tld
+        ));
+    }
+
+    Some(format!("failures spread across {} TLDs, no clear cluster", failed_by_tld.len()))
+}
+
 fn write_single_result(
     file: &mut File,
     index: usize,
     result: &TestResult,
+    direct_failed: Option<&HashSet<String>>,
+    settings: &Settings,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let total = result.successful_domains.len() + result.failed_domains.len();
     
-    writeln!(file, "[{}] Config: {}", index, result.config)?;
+    writeln!(file, "[{}] Config: {}", index, display_config(&result.config, settings))?;
     writeln!(file, "    Port: {}", result.socks5_port)?;
-    writeln!(
+    write!(
         file,
         "    Success Rate: {:.1}% ({}/{})",
         result.success_rate,
         result.successful_domains.len(),
         total
     )?;
+    write_confidence_interval(file, result.confidence_interval(), settings)?;
+    writeln!(file)?;
+    writeln!(file, "    Received: {}", format_megabytes(result.bytes_received))?;
+    if let (Some(started), Some(finished)) = (result.started_sec, result.finished_sec) {
+        writeln!(file, "    Timeline: +{:.1} s → +{:.1} s since run start", started, finished)?;
+    }
+    if let Some(thresholds) = settings.recommend_thresholds {
+        let recommended = result.rank().is_recommended(thresholds);
+        writeln!(file, "    Recommended: {}", if recommended { "yes ★" } else { "no" })?;
+    }
+    if result.network_down {
+        writeln!(file, "    Status: network down during test (control domain unreachable directly)")?;
+    } else if let Some(verdict) = failure_verdict(result, direct_failed) {
+        writeln!(file, "    Failure pattern: {}", verdict)?;
+    }
+    if let Some(reason) = &result.ciadpi_error {
+        writeln!(file, "    ciadpi error: {}", reason)?;
+    }
     writeln!(file)?;
+    write_latency_histogram(file, &result.latencies_ms, &settings.latency_buckets_ms)?;
 
-    if !result.successful_domains.is_empty() {
-        writeln!(file, "    ✓ Successful Domains ({}):", result.successful_domains.len())?;
-        for (i, domain) in result.successful_domains.iter().enumerate() {
-            write!(file, "      {}", domain)?;
-            if (i + 1) % 3 == 0 || i == result.successful_domains.len() - 1 {
-                writeln!(file)?;
-            } else {
-                write!(file, ", ")?;
-            }
+    if let (Some(successes), Some(check)) = (result.persistent_successes, &settings.persistent_check) {
+        writeln!(
+            file,
+            "    Persistent connection: {}/{} requests before drop{}",
+            successes,
+            check.requests,
+            if successes == check.requests { " (held)" } else { "" }
+        )?;
+        writeln!(file)?;
+    }
+
+    if !result.www_variant_domains.is_empty() {
+        writeln!(
+            file,
+            "    www. variant succeeded ({}): {}",
+            result.www_variant_domains.len(),
+            result.www_variant_domains.join(", ")
+        )?;
+        writeln!(file)?;
+    }
+
+    let http3_total = result.http3_successful_domains.len() + result.http3_failed_domains.len();
+    if http3_total > 0 {
+        writeln!(
+            file,
+            "    HTTP/3: {}/{} domains advertising h3 answered over QUIC",
+            result.http3_successful_domains.len(),
+            http3_total
+        )?;
+        if !result.http3_failed_domains.is_empty() {
+            writeln!(file, "      failed: {}", result.http3_failed_domains.join(", "))?;
         }
         writeln!(file)?;
     }
 
+    if !result.resolved_ips.is_empty() {
+        let mut resolved: Vec<(&String, &String)> = result.resolved_ips.iter().collect();
+        resolved.sort();
+        writeln!(file, "    Resolved IPs ({}):", resolved.len())?;
+        for (domain, ip) in resolved {
+            writeln!(file, "      {} → {}", domain, ip)?;
+        }
+        writeln!(file)?;
+    }
+
+    if let Some(rescued) = result.rescued_domains.as_ref().filter(|r| !r.is_empty()) {
+        writeln!(file, "    ★ Rescued Domains, blocked directly ({}):", rescued.len())?;
+        write_domain_list(file, rescued, None)?;
+        writeln!(file)?;
+    }
+
+    if !result.successful_domains.is_empty() && !settings.detail_include_successful {
+        writeln!(file, "    ✓ Successful Domains: {}", result.successful_domains.len())?;
+        writeln!(file)?;
+    } else if !result.successful_domains.is_empty() {
+        writeln!(file, "    ✓ Successful Domains ({}):", result.successful_domains.len())?;
+        write_domain_list(file, &result.successful_domains, settings.max_listed_domains)?;
+        writeln!(file)?;
+    }
+
     if !result.failed_domains.is_empty() {
         writeln!(file, "    ✗ Failed Domains ({}):", result.failed_domains.len())?;
-        for (i, domain) in result.failed_domains.iter().enumerate() {
-            write!(file, "      {}", domain)?;
-            if (i + 1) % 3 == 0 || i == result.failed_domains.len() - 1 {
-                writeln!(file)?;
-            } else {
-                write!(file, ", ")?;
-            }
-        }
+        write_domain_list(file, &result.failed_domains, settings.max_listed_domains)?;
+        writeln!(file)?;
+    }
+
+    if !result.excluded_domains.is_empty() {
+        writeln!(file, "    ○ Excluded Domains ({}):", result.excluded_domains.len())?;
+        write_domain_list(file, &result.excluded_domains, None)?;
         writeln!(file)?;
     }
 
@@ -690,4 +6906,582 @@ fn write_single_result(
     writeln!(file)?;
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn populated_result() -> TestResult {
+        TestResult {
+            config: "-q4+s -s27".to_string(),
+            socks5_port: 10801,
+            successful_domains: vec!["example.com".to_string()],
+            failed_domains: vec!["blocked.example".to_string()],
+            excluded_domains: vec!["down.example".to_string()],
+            failure_reasons: HashMap::from([("blocked.example".to_string(), FailureKind::ProxyAuth)]),
+            bytes_received: 4096,
+            attempts_successful: 3,
+            attempts_total: 6,
+            latencies_ms: vec![120, 340],
+            rescued_domains: Some(vec!["example.com".to_string()]),
+            network_down: true,
+            www_variant_domains: vec!["example.com".to_string()],
+            http3_successful_domains: vec!["example.com".to_string()],
+            http3_failed_domains: vec!["blocked.example".to_string()],
+            persistent_successes: Some(2),
+            resolved_ips: HashMap::from([("example.com".to_string(), "93.184.216.34".to_string())]),
+            ciadpi_error: Some("invalid value".to_string()),
+            started_sec: Some(0.5),
+            finished_sec: Some(4.25),
+            finished_at: Some("2024-01-01T00:00:00+00:00".to_string()),
+            success_rate: 50.0,
+        }
+    }
+
+    /// Every key the object serializes to must be declared in the schema.
+    fn assert_keys_declared(object: &serde_json::Value, schema: &serde_json::Value, path: &str) {
+        let properties = schema["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("{}: schema has no properties", path));
+        for key in object.as_object().expect("object").keys() {
+            assert!(properties.contains_key(key), "{}.{} is missing from results_schema()", path, key);
+        }
+    }
+
+    fn assert_valid(schema: &serde_json::Value, instance: &serde_json::Value) {
+        let validator = jsonschema::validator_for(schema).expect("results_schema() compiles");
+        let errors: Vec<String> = validator
+            .iter_errors(instance)
+            .map(|e| format!("{} at {}", e, e.instance_path()))
+            .collect();
+        assert!(errors.is_empty(), "schema violations: {:#?}", errors);
+    }
+
+    #[test]
+    fn streamed_results_validate_against_schema() {
+        let dir = std::env::temp_dir().join(format!("bdpi_tester_schema_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let results_file = dir.join("results.txt");
+        let settings = settings_with(&format!(
+            "stream_results = true\nresults_file = {:?}\n",
+            results_file.display().to_string()
+        ));
+
+        let mut store = ResultStore::new(&settings).unwrap();
+        store.push(populated_result()).unwrap();
+        store.push(TestResult::new("-d1".to_string(), 10802, DomainResults::default())).unwrap();
+        let written = std::fs::read_to_string(results_file.with_extension("ndjson")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let schema = results_schema();
+        assert_eq!(written.lines().count(), 2);
+        for line in written.lines() {
+            assert_valid(&schema, &serde_json::from_str(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn stream_json_lines_validate_against_schema() {
+        let schema = results_schema();
+        let line_schema = &schema["$defs"]["stream_json_line"];
+        let line = stream_json_line(&populated_result());
+        assert_valid(line_schema, &line);
+        assert_keys_declared(&line, line_schema, "stream_json_line");
+    }
+
+    fn parse(line: &str) -> DomainSpec {
+        DomainSpec::parse(line).unwrap_or_else(|e| panic!("{}: {}", line, e))
+    }
+
+    #[test]
+    fn domain_spec_parses_bare_host() {
+        let spec = parse("example.com");
+        assert_eq!(spec.name, "example.com");
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.port, None);
+        assert_eq!(spec.path, "");
+        assert_eq!(spec.scheme, None);
+        assert_eq!(spec.url(Scheme::Https), "https://example.com");
+        assert_eq!(spec.url(Scheme::Http), "http://example.com");
+    }
+
+    #[test]
+    fn domain_spec_parses_host_and_port() {
+        let spec = parse("https://example.com:8443");
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.port, Some(8443));
+        assert_eq!(spec.scheme, Some(Scheme::Https));
+        assert_eq!(spec.url(Scheme::Https), "https://example.com:8443");
+        assert_eq!(spec.authority(), "example.com:8443");
+        assert!(DomainSpec::parse("example.com:0").is_err());
+        assert!(DomainSpec::parse("example.com:http").is_err());
+    }
+
+    #[test]
+    fn domain_spec_parses_host_and_path() {
+        let spec = parse("example.com/api/v1");
+        assert_eq!(spec.name, "example.com/api/v1");
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.port, None);
+        assert_eq!(spec.path, "/api/v1");
+        assert_eq!(spec.url(Scheme::Https), "https://example.com/api/v1");
+        assert_eq!(spec.www_variant().map(|v| v.url(Scheme::Https)).as_deref(), Some("https://www.example.com/api/v1"));
+    }
+
+    #[test]
+    fn domain_spec_parses_host_port_and_path() {
+        let spec = parse("http://example.com:8080/status/");
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.port, Some(8080));
+        assert_eq!(spec.path, "/status");
+        assert_eq!(spec.scheme, Some(Scheme::Http));
+        assert_eq!(spec.url(Scheme::Http), "http://example.com:8080/status");
+    }
+
+    #[test]
+    fn domain_spec_parses_method_and_body() {
+        let spec = parse(r#"POST api.example.com/v1 content_type=text/plain body={"q": "a b"}"#);
+        assert_eq!(spec.method, reqwest::Method::POST);
+        assert_eq!(spec.host, "api.example.com");
+        assert_eq!(spec.path, "/v1");
+        assert_eq!(spec.content_type, "text/plain");
+        assert_eq!(spec.body.as_deref(), Some(r#"{"q": "a b"}"#));
+        assert!(DomainSpec::parse("G3T example.com").is_err());
+    }
+
+    #[test]
+    fn domain_spec_parses_sni_and_expect() {
+        let spec = parse("example.com/api sni=front.example expect=204");
+        assert_eq!(spec.host, "example.com");
+        assert_eq!(spec.sni.as_deref(), Some("front.example"));
+        assert_eq!(spec.expect_status, Some(204));
+        assert_eq!(spec.url(Scheme::Https), "https://front.example/api");
+        assert_eq!(spec.url(Scheme::Http), "http://example.com/api");
+        assert_eq!(spec.authority(), "example.com");
+        assert!(spec.www_variant().is_none());
+        assert!(DomainSpec::parse("example.com expect=600").is_err());
+        assert!(DomainSpec::parse("example.com retries=3").is_err());
+    }
+
+    #[test]
+    fn domain_fronting_sends_host_without_path() {
+        let spec = parse("example.com:8443/api sni=front.example");
+        let request = spec.request(&reqwest::Client::new(), Scheme::Https).build().expect("request");
+        assert_eq!(request.url().as_str(), "https://front.example:8443/api");
+        assert_eq!(request.headers()[reqwest::header::HOST], "example.com:8443");
+    }
+
+    #[test]
+    #[cfg(feature = "http3")]
+    fn socks_udp_header_round_trips() {
+        let v4: std::net::SocketAddr = "203.0.113.7:443".parse().unwrap();
+        let datagram = socks_udp_datagram(v4, b"quic");
+        assert_eq!(datagram, [0, 0, 0, 0x01, 203, 0, 113, 7, 0x01, 0xbb, b'q', b'u', b'i', b'c']);
+        assert_eq!(parse_socks_udp_header(&datagram), Some((v4, &b"quic"[..])));
+
+        let v6: std::net::SocketAddr = "[2001:db8::1]:8443".parse().unwrap();
+        let datagram = socks_udp_datagram(v6, b"");
+        assert_eq!(datagram.len(), 4 + 16 + 2);
+        assert_eq!(datagram[3], 0x04);
+        assert_eq!(parse_socks_udp_header(&datagram), Some((v6, &b""[..])));
+    }
+
+    #[test]
+    #[cfg(feature = "http3")]
+    fn socks_udp_header_rejects_fragments_and_short_datagrams() {
+        let mut fragment = socks_udp_datagram("203.0.113.7:443".parse().unwrap(), b"quic");
+        fragment[2] = 1;
+        assert_eq!(parse_socks_udp_header(&fragment), None);
+
+        let domain = [0, 0, 0, 0x03, 4, b'h', b'o', b's', b't', 0x01, 0xbb];
+        assert_eq!(parse_socks_udp_header(&domain), None);
+        assert_eq!(parse_socks_udp_header(&[0, 0, 0, 0x01, 203, 0, 113]), None);
+        assert_eq!(parse_socks_udp_header(&[0, 0, 0, 0x01, 203, 0, 113, 7, 0x01]), None);
+        assert_eq!(parse_socks_udp_header(&[]), None);
+    }
+
+    #[test]
+    #[cfg(feature = "browser-tls")]
+    fn browser_tls_profiles_build_connectors() {
+        for fingerprint in [TlsFingerprint::Chrome, TlsFingerprint::Firefox] {
+            let connector = BrowserTlsProfile::of(fingerprint)
+                .connector()
+                .unwrap_or_else(|e| panic!("{}: {}", fingerprint.name(), e));
+            connector.configure().unwrap().into_ssl("example.com").unwrap();
+        }
+    }
+
+    /// Error of a request through a SOCKS5 proxy that answers the greeting with `reply`.
+    async fn socks_greeting_error(reply: &'static [u8]) -> reqwest::Error {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("socks5h://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            let _ = stream.read_exact(&mut greeting).await;
+            let _ = stream.write_all(reply).await;
+        });
+        let client = build_http_client(Some(&proxy), &settings_with("")).unwrap();
+        let error = client.get("http://example.com").send().await.unwrap_err();
+        server.abort();
+        error
+    }
+
+    #[tokio::test]
+    async fn socks_handshake_errors_are_proxy_auth() {
+        let no_method = socks_greeting_error(&[0x05, 0xff]).await;
+        assert_eq!(FailureKind::from_error(&no_method), FailureKind::ProxyAuth, "{:?}", no_method);
+        let bad_version = socks_greeting_error(&[0x04, 0x00]).await;
+        assert_eq!(FailureKind::from_error(&bad_version), FailureKind::ProxyAuth, "{:?}", bad_version);
+        let closed = socks_greeting_error(&[]).await;
+        assert_ne!(FailureKind::from_error(&closed), FailureKind::ProxyAuth, "{:?}", closed);
+    }
+
+    #[test]
+    fn ciadpi_error_line_finds_startup_failures() {
+        let bind = "listening on 127.0.0.1:10800\nbind: Address already in use\n";
+        assert_eq!(ciadpi_error_line(bind).as_deref(), Some("bind: Address already in use"));
+
+        let option = "ciadpi: unrecognized option '--split-any'\nUsage: ciadpi [OPTION...]\n";
+        assert_eq!(ciadpi_error_line(option).as_deref(), Some("ciadpi: unrecognized option '--split-any'"));
+        let value = "  invalid value: -s abc  \n";
+        assert_eq!(ciadpi_error_line(value).as_deref(), Some("invalid value: -s abc"));
+    }
+
+    #[test]
+    fn ciadpi_error_line_ignores_runtime_noise() {
+        let log = "connect: Connection refused\n\
+                   recv: Connection reset by peer\n\
+                   ssl error, failed to desync\n\
+                   rebinding socket after error\n";
+        assert_eq!(ciadpi_error_line(log), None);
+        assert_eq!(ciadpi_error_line(""), None);
+    }
+
+    fn assert_interval(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 0.1 && (actual.1 - expected.1).abs() < 0.1,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn wilson_interval_matches_known_values() {
+        assert_eq!(wilson_interval(0, 0), (0.0, 0.0));
+        assert_interval(wilson_interval(5, 10), (23.7, 76.3));
+        assert_interval(wilson_interval(0, 10), (0.0, 27.8));
+        assert_interval(wilson_interval(10, 10), (72.2, 100.0));
+        assert_interval(wilson_interval(1, 1), (20.7, 100.0));
+    }
+
+    #[test]
+    fn repeated_attempts_need_a_majority_without_quorum() {
+        let settings = settings_with("repeat_count = 4");
+        assert!(attempts_passed(3, 4, &settings));
+        assert!(!attempts_passed(2, 4, &settings));
+        assert!(attempts_passed(1, 1, &settings_with("")));
+        assert!(!attempts_passed(0, 1, &settings_with("")));
+    }
+
+    #[test]
+    fn repeated_attempts_use_the_attempts_quorum() {
+        let shared = settings_with("repeat_count = 3\ndomain_success_quorum = 1");
+        assert!(attempts_passed(1, 3, &shared));
+        assert!(!attempts_passed(0, 3, &shared));
+
+        let split = settings_with("repeat_count = 5\ndomain_success_quorum = { attempts = 4, runs = 1 }");
+        assert!(attempts_passed(4, 5, &split));
+        assert!(!attempts_passed(3, 5, &split));
+
+        let runs_only = settings_with("repeat_count = 3\ndomain_success_quorum = { runs = 2 }");
+        assert!(!attempts_passed(1, 3, &runs_only), "without an attempts quorum the majority rule applies");
+        assert!(attempts_passed(2, 3, &runs_only));
+
+        let single = settings_with("domain_success_quorum = 2");
+        assert!(attempts_passed(1, 1, &single), "the quorum only applies with repeat_count above 1");
+    }
+
+    /// Report over `domains` where each config reached the listed domains, with
+    /// the given latency, and failed the rest.
+    fn report_of(domains: &[&str], configs: &[(&str, &[&str], u64)]) -> ReportSummary {
+        let mut summary = ReportSummary::default();
+        for &(config, reached, latency_ms) in configs {
+            let results = DomainResults {
+                successful: reached.iter().map(|d| d.to_string()).collect(),
+                failed: domains.iter().filter(|d| !reached.contains(d)).map(|d| d.to_string()).collect(),
+                latencies_ms: vec![latency_ms],
+                ..DomainResults::default()
+            };
+            summary.add(&TestResult::new(config.to_string(), 10800, results));
+        }
+        summary
+    }
+
+    #[test]
+    fn domain_bits_span_several_words() {
+        let mut bits = DomainBits::default();
+        for id in [3, 64, 130] {
+            bits.insert(id);
+        }
+        assert_eq!(bits.ids().collect::<Vec<_>>(), vec![3, 64, 130]);
+        assert_eq!(bits.len(), 3);
+
+        let mut small = DomainBits::default();
+        small.insert(64);
+        assert!(small.is_subset(&bits));
+        assert!(!bits.is_subset(&small));
+        assert_eq!(bits.count_outside(&small), 2);
+        assert_eq!(bits.intersection(&small).ids().collect::<Vec<_>>(), vec![64]);
+        assert!(DomainBits::default().is_empty());
+    }
+
+    #[test]
+    fn covering_set_picks_greedily_and_breaks_ties_by_test_order() {
+        let domains = ["a", "b", "c", "d", "e"];
+        let summary = report_of(
+            &domains,
+            &[
+                ("c1", &["a", "b", "c"], 100),
+                ("c2", &["c", "d"], 100),
+                ("c3", &["a", "b", "c"], 100),
+                ("c4", &["e"], 100),
+                ("c5", &[], 100),
+            ],
+        );
+        assert_eq!(summary.covering_set(), vec![("c1", 3), ("c2", 1), ("c4", 1)]);
+        assert_eq!(summary.coverage(), (5, 5));
+    }
+
+    #[test]
+    fn dominated_configs_reach_nothing_new_and_are_not_faster() {
+        let domains = ["a", "b", "c"];
+        let summary = report_of(
+            &domains,
+            &[
+                ("fast_full", &["a", "b", "c"], 100),
+                ("slow_partial", &["a", "b"], 200),
+                ("faster_partial", &["a", "b"], 50),
+                ("same_as_first", &["a", "b", "c"], 100),
+                ("nothing", &[], 10),
+            ],
+        );
+        let dominated: Vec<(&str, &str, usize, usize)> = summary
+            .dominated()
+            .into_iter()
+            .map(|(rank, other, domains, other_domains)| {
+                (rank.config.as_str(), other.config.as_str(), domains, other_domains)
+            })
+            .collect();
+        assert_eq!(
+            dominated,
+            vec![("slow_partial", "fast_full", 2, 3), ("same_as_first", "fast_full", 3, 3)]
+        );
+    }
+
+    #[test]
+    fn alt_svc_h3_port_reads_the_h3_entry() {
+        assert_eq!(alt_svc_h3_port(r#"h3=":443"; ma=86400"#), Some(443));
+        assert_eq!(alt_svc_h3_port(r#"h3-29=":443", h3="alt.example:8443""#), Some(8443));
+        assert_eq!(alt_svc_h3_port(r#"h2=":443""#), None);
+        assert_eq!(alt_svc_h3_port("clear"), None);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.append(reqwest::header::ALT_SVC, "clear".parse().unwrap());
+        headers.append(reqwest::header::ALT_SVC, r#"h3=":443"; ma=86400"#.parse().unwrap());
+        assert_eq!(advertised_h3_port(&headers), Some(443));
+    }
+
+    #[test]
+    fn schema_declares_every_serialized_field() {
+        let record = serde_json::to_value(populated_result()).unwrap();
+        assert_keys_declared(&record, &results_schema(), "$");
+    }
+
+    /// Built-in settings with the keys of `overrides` replaced.
+    fn settings_with(overrides: &str) -> Settings {
+        let mut table: toml::Table = BUILTIN_SETTINGS.parse().unwrap();
+        table.extend(overrides.parse::<toml::Table>().unwrap());
+        table.try_into().unwrap()
+    }
+
+    #[test]
+    fn group_ports_reuse_the_same_range_without_rotation() {
+        let settings = settings_with("group_size = 3\nstart_port = 2000\n");
+        assert_eq!(port_generation(&settings, 5), 0);
+        assert_eq!(group_ports(&settings, 0, 3), vec![2000, 2001, 2002]);
+        assert_eq!(group_ports(&settings, 5, 2), vec![2000, 2001]);
+    }
+
+    #[test]
+    fn group_ports_rotate_after_max_reuses() {
+        let settings = settings_with("group_size = 3\nstart_port = 2000\nmax_reuses_per_port = 1\n");
+        let generations: Vec<usize> = (0..5).map(|group| port_generation(&settings, group)).collect();
+        assert_eq!(generations, vec![0, 0, 1, 1, 2]);
+        assert_eq!(group_ports(&settings, 1, 3), vec![2000, 2001, 2002]);
+        assert_eq!(group_ports(&settings, 2, 3), vec![2003, 2004, 2005]);
+        assert_eq!(group_ports(&settings, 4, 1), vec![2006]);
+    }
+
+    #[test]
+    fn group_ports_take_pool_ports_in_order_and_wrap() {
+        let pool = "port_pool = [1080, 1443, 8080, 8443, 9000]\n";
+        let settings = settings_with(&format!("group_size = 2\n{}", pool));
+        assert_eq!(group_ports(&settings, 0, 2), vec![1080, 1443]);
+        assert_eq!(group_ports(&settings, 3, 2), vec![1080, 1443]);
+
+        let settings = settings_with(&format!("group_size = 2\nmax_reuses_per_port = 0\n{}", pool));
+        assert_eq!(group_ports(&settings, 1, 2), vec![8080, 8443]);
+        assert_eq!(group_ports(&settings, 2, 2), vec![9000, 1080]);
+    }
+
+    #[test]
+    fn port_pool_must_fit_rotation() {
+        let small = settings_with("group_size = 3\nport_pool = [1, 2, 3, 4, 5]\nmax_reuses_per_port = 1\n");
+        assert!(validate_port_pool(&small, 3).is_ok(), "a single group never rotates");
+        assert!(validate_port_pool(&small, 6).is_ok(), "the second group still reuses the first ports");
+        assert!(validate_port_pool(&small, 7).is_err());
+
+        let enough = settings_with("group_size = 3\nport_pool = [1, 2, 3, 4, 5, 6]\nmax_reuses_per_port = 1\n");
+        assert!(validate_port_pool(&enough, 100).is_ok());
+
+        let no_rotation = settings_with("group_size = 3\nport_pool = [1, 2, 3]\n");
+        assert!(validate_port_pool(&no_rotation, 100).is_ok());
+        assert!(validate_port_pool(&settings_with("group_size = 3\nport_pool = [1, 2]\n"), 3).is_err());
+    }
+
+    #[test]
+    fn fuzz_configs_fill_placeholders_without_duplicates() {
+        let configs = generate_fuzz_configs("-s{1..3} -d{a|b}", 6).unwrap();
+        let mut sorted = configs.clone();
+        sorted.sort();
+        assert_eq!(sorted, vec!["-s1 -da", "-s1 -db", "-s2 -da", "-s2 -db", "-s3 -da", "-s3 -db"]);
+
+        let exhausted = generate_fuzz_configs("-s{-1..0}", 5).unwrap();
+        assert_eq!(exhausted.len(), 2, "a template with fewer variants than requested yields them all once");
+    }
+
+    #[test]
+    fn fuzz_configs_reject_bad_templates() {
+        assert!(generate_fuzz_configs("-s1 -d1", 3).is_err());
+        assert!(generate_fuzz_configs("-s{5..1}", 3).is_err());
+        assert!(generate_fuzz_configs("-s{1..x}", 3).is_err());
+        assert!(generate_fuzz_configs("-s{1..3} -d{any}", 3).is_err());
+    }
+
+    #[test]
+    fn config_args_keep_separate_values_with_their_flag() {
+        assert_eq!(
+            split_config_args("-q4+s --split 2  -d1 -A torst,redirect"),
+            vec!["-q4+s", "--split 2", "-d1", "-A torst,redirect"]
+        );
+        assert_eq!(split_config_args("--fake -1 --ttl 8 4"), vec!["--fake", "-1", "--ttl 8 4"]);
+        assert!(split_config_args("   ").is_empty());
+    }
+
+    const PROFILED_SETTINGS: &str = r#"
+group_size = 10
+request_timeout_sec = 5
+
+[profiles.quick]
+group_size = 20
+repeat_count = 1
+
+[profiles.thorough]
+repeat_count = 5
+"#;
+
+    #[test]
+    fn profile_overrides_base_settings_and_drops_the_others() {
+        let merged: toml::Table = apply_profile(PROFILED_SETTINGS, "quick").unwrap().parse().unwrap();
+        assert_eq!(merged["group_size"].as_integer(), Some(20));
+        assert_eq!(merged["repeat_count"].as_integer(), Some(1));
+        assert_eq!(merged["request_timeout_sec"].as_integer(), Some(5));
+        assert!(!merged.contains_key("profiles"));
+    }
+
+    #[test]
+    fn unknown_profile_lists_the_available_ones() {
+        let error = apply_profile(PROFILED_SETTINGS, "fast").unwrap_err().to_string();
+        assert!(error.contains("'fast'") && error.contains("quick") && error.contains("thorough"), "{}", error);
+        assert!(apply_profile("group_size = 10", "quick").is_err());
+    }
+
+    #[test]
+    fn duration_args_take_an_optional_unit() {
+        assert_eq!(parse_duration_arg("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration_arg("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration_arg("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration_arg("2h").unwrap(), Duration::from_secs(7200));
+        for invalid in ["", "0", "0m", "m", "-5", "1.5h", "10d", "5 m"] {
+            assert!(parse_duration_arg(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn stale_sessions_respect_both_age_and_keep_last() {
+        let day = |d: u32| chrono::NaiveDate::from_ymd_opt(2024, 1, d).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        let sessions: Vec<(PathBuf, chrono::NaiveDateTime)> =
+            [5, 4, 3, 2, 1].iter().map(|&d| (PathBuf::from(format!("s{}", d)), day(d))).collect();
+        let names = |stale: Vec<&PathBuf>| stale.iter().map(|p| p.display().to_string()).collect::<Vec<_>>();
+
+        assert_eq!(names(stale_sessions(&sessions, None, Some(2))), vec!["s3", "s2", "s1"]);
+        assert_eq!(names(stale_sessions(&sessions, Some(day(3)), None)), vec!["s2", "s1"]);
+        assert_eq!(names(stale_sessions(&sessions, Some(day(5)), Some(3))), vec!["s2", "s1"]);
+        assert_eq!(names(stale_sessions(&sessions, Some(day(2)), Some(1))), vec!["s1"]);
+        assert!(stale_sessions(&sessions, None, Some(10)).is_empty());
+    }
+
+    fn result_with(successful: &[&str], failed: &[&str]) -> TestResult {
+        let results = DomainResults {
+            successful: successful.iter().map(|d| d.to_string()).collect(),
+            failed: failed.iter().map(|d| d.to_string()).collect(),
+            ..DomainResults::default()
+        };
+        TestResult::new("-d1".to_string(), 10800, results)
+    }
+
+    #[test]
+    fn failure_verdict_needs_two_failures() {
+        assert_eq!(failure_verdict(&result_with(&["a.com"], &["b.ru"]), None), None);
+    }
+
+    #[test]
+    fn failure_verdict_blames_directly_unreachable_domains_first() {
+        let result = result_with(&["x.com"], &["a.ru", "b.ru", "c.com"]);
+        let direct_failed: HashSet<String> = ["a.ru".to_string(), "c.com".to_string()].into();
+        assert_eq!(
+            failure_verdict(&result, Some(&direct_failed)).unwrap(),
+            "fails mostly on domains unreachable directly too (2/3 failures)"
+        );
+
+        let unrelated: HashSet<String> = ["x.com".to_string()].into();
+        assert_eq!(
+            failure_verdict(&result, Some(&unrelated)).unwrap(),
+            "fails mostly on .ru domains (2/3 failures, 2 of 2 .ru domains failed)"
+        );
+    }
+
+    #[test]
+    fn failure_verdict_finds_tld_clusters() {
+        let clustered = result_with(&["x.com", "y.ru"], &["a.ru", "b.ru", "c.com"]);
+        assert_eq!(
+            failure_verdict(&clustered, None).unwrap(),
+            "fails mostly on .ru domains (2/3 failures, 2 of 3 .ru domains failed)"
+        );
+
+        let spread = result_with(&[], &["a.ru", "b.com", "c.org"]);
+        assert_eq!(failure_verdict(&spread, None).unwrap(), "failures spread across 3 TLDs, no clear cluster");
+
+        let one_tld = result_with(&["x.com"], &["a.com", "b.com"]);
+        assert_eq!(
+            failure_verdict(&one_tld, None).unwrap(),
+            "failures spread across 1 TLDs, no clear cluster",
+            "a TLD is no cluster when every tested domain shares it"
+        );
+    }
+}